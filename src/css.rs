@@ -1,24 +1,380 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
 #[derive(Debug)]
 pub struct Stylesheet {
     pub rules: Vec<Rule>,
 }
 
+/// Rules bucketed by the id, class, or tag name their selectors require, so that
+/// matching an element only has to look at rules that could possibly apply to it.
+#[derive(Debug)]
+pub struct RuleIndex<'a> {
+    pub rules: &'a [Rule],
+    by_id: HashMap<&'a str, Vec<usize>>,
+    by_class: HashMap<&'a str, Vec<usize>>,
+    by_tag: HashMap<&'a str, Vec<usize>>,
+    universal: Vec<usize>,
+}
+
+impl Stylesheet {
+    pub fn build_index(&self) -> RuleIndex<'_> {
+        let mut index = RuleIndex {
+            rules: &self.rules,
+            by_id: HashMap::new(),
+            by_class: HashMap::new(),
+            by_tag: HashMap::new(),
+            universal: Vec::new(),
+        };
+        for (i, rule) in self.rules.iter().enumerate() {
+            for selector in &rule.selectors {
+                let Selector::Simple(ref simple) = *selector;
+                // The most selective part of the selector decides the bucket.
+                let bucket = if let Some(ref id) = simple.id {
+                    index.by_id.entry(id).or_default()
+                } else if let Some(class) = simple.class.first() {
+                    index.by_class.entry(class).or_default()
+                } else if let Some(ref tag_name) = simple.tag_name {
+                    index.by_tag.entry(tag_name).or_default()
+                } else {
+                    &mut index.universal
+                };
+                if bucket.last() != Some(&i) {
+                    bucket.push(i);
+                }
+            }
+        }
+        index
+    }
+
+    /// Check every declaration against the properties this engine understands,
+    /// without changing the stylesheet.
+    pub fn validate(&self) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+        for declaration in self.rules.iter().flat_map(|rule| &rule.declarations) {
+            let name = &declaration.name;
+            match accepts_value(name, &declaration.value) {
+                None => warnings.push(Warning::UnknownProperty(name.clone())),
+                Some(false) => warnings.push(Warning::InvalidValue(
+                    name.clone(),
+                    declaration.value.clone(),
+                )),
+                Some(true) => {}
+            }
+        }
+        warnings
+    }
+
+    /// Serialize the stylesheet back to CSS that `parse` reads as the same rules.
+    pub fn to_css(&self) -> String {
+        let mut css = String::new();
+        for rule in &self.rules {
+            let selectors: Vec<String> = rule.selectors.iter().map(Selector::to_css).collect();
+            css.push_str(&selectors.join(", "));
+            css.push_str(" {\n");
+            for declaration in &rule.declarations {
+                css.push_str(&format!(
+                    "  {}: {};\n",
+                    declaration.name,
+                    declaration.value.to_css()
+                ));
+            }
+            css.push_str("}\n");
+        }
+        css
+    }
+}
+
+/// A problem found by `Stylesheet::validate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    UnknownProperty(String),
+    InvalidValue(String, Value),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::UnknownProperty(ref name) => write!(f, "unknown property `{}`", name),
+            Warning::InvalidValue(ref name, ref value) => {
+                write!(f, "invalid value {:?} for `{}`", value, name)
+            }
+        }
+    }
+}
+
+// どのプロパティにも指定できるキーワード
+const GLOBAL_KEYWORDS: &[&str] = &["inherit", "initial", "unset", "revert"];
+
+// 知らないプロパティならNoneを返す。キーワードは全体共通のものと、プロパティごとのものだけ受け付ける
+fn accepts_value(name: &str, value: &Value) -> Option<bool> {
+    // カスタムプロパティはどんな値でも持てる。var()の値はスタイルの計算時まで分からない
+    if name.starts_with("--") {
+        return Some(true);
+    }
+    if matches!(value, Value::Var(..)) {
+        return accepts_value(name, &Value::keyword("initial"));
+    }
+    let is_length =
+        matches!(value, Value::Length(..) | Value::Calc(_)) || value.as_number() == Some(0.0);
+    let is_color = matches!(value, Value::ColorValue(_));
+    let accepted = match name {
+        "width"
+        | "height"
+        | "min-width"
+        | "max-width"
+        | "min-height"
+        | "max-height"
+        | "inline-size"
+        | "block-size"
+        | "min-inline-size"
+        | "max-inline-size"
+        | "min-block-size"
+        | "max-block-size"
+        | "margin"
+        | "margin-top"
+        | "margin-right"
+        | "margin-bottom"
+        | "margin-left"
+        | "padding"
+        | "padding-top"
+        | "padding-right"
+        | "padding-bottom"
+        | "padding-left"
+        | "border-width"
+        | "outline-width"
+        | "border-top-width"
+        | "border-right-width"
+        | "border-bottom-width"
+        | "border-left-width"
+        | "font-size"
+        | "letter-spacing"
+        | "top"
+        | "right"
+        | "bottom"
+        | "left"
+        | "gap"
+        | "column-gap"
+        | "row-gap" => is_length,
+        "color"
+        | "background-color"
+        | "border-color"
+        | "outline-color"
+        | "border-top-color"
+        | "border-right-color"
+        | "border-bottom-color"
+        | "border-left-color" => is_color,
+        "background-image" => matches!(value, Value::Url(_)),
+        "opacity" | "flex-grow" | "z-index" => matches!(value, Value::Number(_)),
+        "line-height" => is_length || matches!(value, Value::Number(_)),
+        "font-weight" => matches!(value, Value::Number(_)),
+        "font-family" | "content" => matches!(value, Value::Str(_)),
+        "display"
+        | "position"
+        | "float"
+        | "visibility"
+        | "font-style"
+        | "text-decoration"
+        | "text-align"
+        | "border-style"
+        | "white-space"
+        | "overflow-wrap"
+        | "word-break"
+        | "direction"
+        | "justify-content"
+        | "vertical-align"
+        | "outline-style"
+        | "border-top-style"
+        | "border-right-style"
+        | "border-bottom-style"
+        | "border-left-style" => false,
+        _ => return None,
+    };
+    match *value {
+        // フォント名は引用符なしでも書けるので、どんな識別子でもよい
+        Value::Keyword(_) if name == "font-family" => Some(true),
+        Value::Keyword(ref keyword) => Some(
+            GLOBAL_KEYWORDS
+                .iter()
+                .chain(property_keywords(name))
+                .any(|known| known.eq_ignore_ascii_case(keyword)),
+        ),
+        _ => Some(accepted),
+    }
+}
+
+// プロパティ固有のキーワード
+fn property_keywords(name: &str) -> &'static [&'static str] {
+    match name {
+        "width" | "height" | "min-width" | "min-height" | "inline-size" | "block-size"
+        | "min-inline-size" | "min-block-size" | "margin" | "margin-top" | "margin-right"
+        | "margin-bottom" | "margin-left" | "top" | "right" | "bottom" | "left" | "z-index" => {
+            &["auto"]
+        }
+        "max-width" | "max-height" | "max-inline-size" | "max-block-size" | "background-image" => {
+            &["none"]
+        }
+        "border-width"
+        | "outline-width"
+        | "border-top-width"
+        | "border-right-width"
+        | "border-bottom-width"
+        | "border-left-width" => &["thin", "medium", "thick"],
+        "font-size" => &[
+            "xx-small",
+            "x-small",
+            "small",
+            "medium",
+            "large",
+            "x-large",
+            "xx-large",
+            "xxx-large",
+            "smaller",
+            "larger",
+        ],
+        "letter-spacing" | "line-height" | "gap" | "column-gap" | "row-gap" => &["normal"],
+        "color"
+        | "background-color"
+        | "border-color"
+        | "outline-color"
+        | "border-top-color"
+        | "border-right-color"
+        | "border-bottom-color"
+        | "border-left-color" => &["currentcolor"],
+        "font-weight" => &["normal", "bold", "bolder", "lighter"],
+        "content" => &["none", "normal"],
+        "display" => &[
+            "block",
+            "inline",
+            "inline-block",
+            "flex",
+            "table",
+            "table-row",
+            "table-cell",
+            "none",
+        ],
+        "position" => &["static", "relative", "absolute", "fixed", "sticky"],
+        "float" => &["none", "left", "right"],
+        "visibility" => &["visible", "hidden", "collapse"],
+        "font-style" => &["normal", "italic", "oblique"],
+        "text-decoration" => &["none", "underline", "overline", "line-through"],
+        "text-align" => &["left", "right", "center", "justify", "start", "end"],
+        "border-style"
+        | "outline-style"
+        | "border-top-style"
+        | "border-right-style"
+        | "border-bottom-style"
+        | "border-left-style" => &[
+            "none", "hidden", "dotted", "dashed", "solid", "double", "groove", "ridge", "inset",
+            "outset",
+        ],
+        "white-space" => &[
+            "normal",
+            "nowrap",
+            "pre",
+            "pre-wrap",
+            "pre-line",
+            "break-spaces",
+        ],
+        "overflow-wrap" => &["normal", "break-word", "anywhere"],
+        "word-break" => &["normal", "break-all", "keep-all", "break-word"],
+        "direction" => &["ltr", "rtl"],
+        "justify-content" => &[
+            "normal",
+            "flex-start",
+            "flex-end",
+            "center",
+            "space-between",
+            "space-around",
+            "space-evenly",
+            "start",
+            "end",
+            "left",
+            "right",
+        ],
+        "vertical-align" => &[
+            "baseline",
+            "sub",
+            "super",
+            "text-top",
+            "text-bottom",
+            "middle",
+            "top",
+            "bottom",
+        ],
+        _ => &[],
+    }
+}
+
+impl<'a> RuleIndex<'a> {
+    /// Return the rules that may match an element, in stylesheet order.
+    pub fn candidates(&self, tag_name: &str, id: Option<&str>, classes: &[&str]) -> Vec<&'a Rule> {
+        let mut indices = self.universal.clone();
+        if let Some(rules) = self.by_tag.get(tag_name) {
+            indices.extend(rules);
+        }
+        if let Some(rules) = id.and_then(|id| self.by_id.get(id)) {
+            indices.extend(rules);
+        }
+        for class in classes {
+            if let Some(rules) = self.by_class.get(class) {
+                indices.extend(rules);
+            }
+        }
+        indices.sort();
+        indices.dedup();
+        indices.into_iter().map(|i| &self.rules[i]).collect()
+    }
+}
+
 #[derive(Debug)]
 pub struct Rule {
     pub selectors: Vec<Selector>,
     pub declarations: Vec<Declaration>,
 }
 
+impl Rule {
+    /// The rule's selectors, most specific first.
+    pub fn selectors(&self) -> &[Selector] {
+        &self.selectors
+    }
+
+    /// The rule's declarations in source order, with shorthands already expanded.
+    pub fn declarations(&self) -> &[Declaration] {
+        &self.declarations
+    }
+}
+
 #[derive(Debug)]
 pub enum Selector {
     Simple(SimpleSelector),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SimpleSelector {
     pub tag_name: Option<String>,
     pub id: Option<String>,
     pub class: Vec<String>,
+    pub pseudo_classes: Vec<PseudoClass>,
+    pub pseudo_element: Option<PseudoElement>,
+    // Computed once at parse time, since it's needed for every match.
+    pub specificity: Specificity,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PseudoClass {
+    Hover,
+    // 文書のルート要素
+    Root,
+    // `:not()` takes a single simple selector.
+    Not(Box<SimpleSelector>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PseudoElement {
+    Before,
+    After,
 }
 
 #[derive(Debug)]
@@ -27,25 +383,190 @@ pub struct Declaration {
     pub value: Value,
 }
 
+impl Declaration {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
-    Keyword(String),
+    Keyword(Cow<'static, str>),
     Length(f32, Unit),
+    Number(f32),
+    Str(String),
+    Url(String),
     ColorValue(Color),
+    Calc(Box<CalcExpr>),
+    // `var(--name, fallback)`。スタイルの計算時にカスタムプロパティの値に置き換えられる
+    Var(String, Option<Box<Value>>),
+    // 空白で区切った複数の値。今のところカスタムプロパティの値にだけ使う
+    List(Vec<Value>),
+}
+
+/// A `calc()` expression. Lengths in it are resolved at layout time, since
+/// percentages depend on the containing block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalcExpr {
+    // 長さか数値
+    Value(Value),
+    Add(Box<CalcExpr>, Box<CalcExpr>),
+    Sub(Box<CalcExpr>, Box<CalcExpr>),
+    Mul(Box<CalcExpr>, Box<CalcExpr>),
+    Div(Box<CalcExpr>, Box<CalcExpr>),
 }
 
+impl CalcExpr {
+    /// Evaluate the expression, resolving each length with `resolve`. Numbers are
+    /// used as-is, so `2 * 10px` is 20.
+    pub fn evaluate(&self, resolve: &dyn Fn(&Value) -> f32) -> f32 {
+        match *self {
+            CalcExpr::Value(Value::Number(n)) => n,
+            CalcExpr::Value(ref value) => resolve(value),
+            CalcExpr::Add(ref a, ref b) => a.evaluate(resolve) + b.evaluate(resolve),
+            CalcExpr::Sub(ref a, ref b) => a.evaluate(resolve) - b.evaluate(resolve),
+            CalcExpr::Mul(ref a, ref b) => a.evaluate(resolve) * b.evaluate(resolve),
+            CalcExpr::Div(ref a, ref b) => a.evaluate(resolve) / b.evaluate(resolve),
+        }
+    }
+
+    fn has_percentage(&self) -> bool {
+        match *self {
+            CalcExpr::Value(ref value) => value.has_percentage(),
+            CalcExpr::Add(ref a, ref b)
+            | CalcExpr::Sub(ref a, ref b)
+            | CalcExpr::Mul(ref a, ref b)
+            | CalcExpr::Div(ref a, ref b) => a.has_percentage() || b.has_percentage(),
+        }
+    }
+
+    fn to_css(&self) -> String {
+        match *self {
+            CalcExpr::Value(ref value) => value.to_css(),
+            CalcExpr::Add(ref a, ref b) => format!("({} + {})", a.to_css(), b.to_css()),
+            CalcExpr::Sub(ref a, ref b) => format!("({} - {})", a.to_css(), b.to_css()),
+            CalcExpr::Mul(ref a, ref b) => format!("({} * {})", a.to_css(), b.to_css()),
+            CalcExpr::Div(ref a, ref b) => format!("({} / {})", a.to_css(), b.to_css()),
+        }
+    }
+}
+
+// Keywords that show up constantly are shared instead of allocated per value.
+const COMMON_KEYWORDS: &[&str] = &[
+    "auto", "none", "block", "inline", "normal", "bold", "italic", "hidden", "visible", "static",
+    "relative", "absolute", "solid", "inherit", "initial", "medium",
+];
+
 impl Value {
+    pub fn keyword(name: &str) -> Value {
+        match COMMON_KEYWORDS.iter().find(|&&keyword| keyword == name) {
+            Some(&keyword) => Value::Keyword(Cow::Borrowed(keyword)),
+            None => Value::Keyword(Cow::Owned(name.to_string())),
+        }
+    }
+
+    pub fn as_url(&self) -> Option<&str> {
+        match *self {
+            Value::Url(ref url) => Some(url),
+            _ => None,
+        }
+    }
+
+    /// Return the value of a unitless number such as `opacity: 0.5`. Lengths aren't numbers.
+    pub fn as_number(&self) -> Option<f32> {
+        match *self {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
     pub fn to_px(&self) -> f32 {
         match *self {
             Value::Length(f, Unit::Px) => f,
             _ => 0.0,
         }
     }
+
+    /// Like `to_px`, but also resolves `em` and `rem` lengths against the given font sizes.
+    pub fn to_px_relative(&self, font_size: f32, root_font_size: f32) -> f32 {
+        match *self {
+            Value::Length(f, Unit::Em) => f * font_size,
+            Value::Length(f, Unit::Rem) => f * root_font_size,
+            // Without font metrics, the x-height and the width of `0` are both taken
+            // to be half the font size, matching the monospace text measurer.
+            Value::Length(f, Unit::Ex) | Value::Length(f, Unit::Ch) => f * font_size * 0.5,
+            _ => self.to_px(),
+        }
+    }
+
+    /// Whether the value is a percentage, or a `calc()` expression that uses one.
+    pub fn has_percentage(&self) -> bool {
+        match *self {
+            Value::Length(_, Unit::Percent) => true,
+            Value::Calc(ref expr) => expr.has_percentage(),
+            _ => false,
+        }
+    }
+
+    pub fn to_css(&self) -> String {
+        match *self {
+            Value::Keyword(ref keyword) => keyword.to_string(),
+            Value::Length(f, ref unit) => format!("{}{}", f, unit.to_css()),
+            Value::Number(n) => n.to_string(),
+            Value::Str(ref s) => quote(s),
+            Value::Url(ref url) => format!("url({})", quote(url)),
+            Value::ColorValue(color) => color.to_css(),
+            // 二項演算の式は括弧で囲まれているので、それをcalc()の括弧にする
+            Value::Calc(ref expr) => match **expr {
+                CalcExpr::Value(ref value) => format!("calc({})", value.to_css()),
+                _ => format!("calc{}", expr.to_css()),
+            },
+            Value::Var(ref name, None) => format!("var({})", name),
+            Value::Var(ref name, Some(ref fallback)) => {
+                format!("var({}, {})", name, fallback.to_css())
+            }
+            Value::List(ref values) => {
+                let values: Vec<String> = values.iter().map(Value::to_css).collect();
+                values.join(" ")
+            }
+        }
+    }
+}
+
+// 文字列はエスケープできないので、中に含まれない方の引用符で囲む
+fn quote(s: &str) -> String {
+    if s.contains('"') {
+        format!("'{}'", s)
+    } else {
+        format!("\"{}\"", s)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Unit {
     Px,
+    Em,
+    Rem,
+    Ex,
+    Ch,
+    Percent,
+}
+
+impl Unit {
+    pub fn to_css(&self) -> &'static str {
+        match *self {
+            Unit::Px => "px",
+            Unit::Em => "em",
+            Unit::Rem => "rem",
+            Unit::Ex => "ex",
+            Unit::Ch => "ch",
+            Unit::Percent => "%",
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Copy)]
@@ -56,35 +577,313 @@ pub struct Color {
     pub a: u8,
 }
 
+impl Color {
+    /// Parse a `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa` hex color.
+    pub fn from_hex(s: &str) -> Option<Color> {
+        let hex = s.strip_prefix('#')?;
+        if !hex.is_ascii() {
+            return None;
+        }
+        // 短い形式は各桁を繰り返す。`#fff`は`#ffffff`と同じ
+        let digits = match hex.len() {
+            3 | 4 => 1,
+            6 | 8 => 2,
+            _ => return None,
+        };
+        let channel = |i: usize| {
+            let value = u8::from_str_radix(&hex[i * digits..(i + 1) * digits], 16).ok()?;
+            Some(if digits == 1 { value * 0x11 } else { value })
+        };
+        Some(Color {
+            r: channel(0)?,
+            g: channel(1)?,
+            b: channel(2)?,
+            a: if hex.len() == 4 || hex.len() == 8 {
+                channel(3)?
+            } else {
+                255
+            },
+        })
+    }
+
+    /// Look up a CSS named color such as `coral`, ignoring case. `transparent` is
+    /// included as fully transparent black.
+    pub fn from_named(name: &str) -> Option<Color> {
+        let name = name.to_ascii_lowercase();
+        if name == "transparent" {
+            return Some(Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 0,
+            });
+        }
+        let i = NAMED_COLORS
+            .binary_search_by(|&(named, _)| named.cmp(&name))
+            .ok()?;
+        let rgb = NAMED_COLORS[i].1;
+        Some(Color {
+            r: (rgb >> 16) as u8,
+            g: (rgb >> 8) as u8,
+            b: rgb as u8,
+            a: 255,
+        })
+    }
+
+    /// Format as `#rrggbb`, `transparent` for transparent black, or `#rrggbbaa` otherwise.
+    pub fn to_css(&self) -> String {
+        // 透明でも色の成分が残っていれば、`transparent`にすると黒に変わってしまう
+        if (self.r, self.g, self.b, self.a) == (0, 0, 0, 0) {
+            return "transparent".to_string();
+        }
+        let rgb = format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b);
+        if self.a == 255 {
+            rgb
+        } else {
+            format!("{}{:02x}", rgb, self.a)
+        }
+    }
+}
+
+// CSSの名前付きの色。名前順に並べて二分探索する
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("aliceblue", 0xf0f8ff),
+    ("antiquewhite", 0xfaebd7),
+    ("aqua", 0x00ffff),
+    ("aquamarine", 0x7fffd4),
+    ("azure", 0xf0ffff),
+    ("beige", 0xf5f5dc),
+    ("bisque", 0xffe4c4),
+    ("black", 0x000000),
+    ("blanchedalmond", 0xffebcd),
+    ("blue", 0x0000ff),
+    ("blueviolet", 0x8a2be2),
+    ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887),
+    ("cadetblue", 0x5f9ea0),
+    ("chartreuse", 0x7fff00),
+    ("chocolate", 0xd2691e),
+    ("coral", 0xff7f50),
+    ("cornflowerblue", 0x6495ed),
+    ("cornsilk", 0xfff8dc),
+    ("crimson", 0xdc143c),
+    ("cyan", 0x00ffff),
+    ("darkblue", 0x00008b),
+    ("darkcyan", 0x008b8b),
+    ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9),
+    ("darkgreen", 0x006400),
+    ("darkgrey", 0xa9a9a9),
+    ("darkkhaki", 0xbdb76b),
+    ("darkmagenta", 0x8b008b),
+    ("darkolivegreen", 0x556b2f),
+    ("darkorange", 0xff8c00),
+    ("darkorchid", 0x9932cc),
+    ("darkred", 0x8b0000),
+    ("darksalmon", 0xe9967a),
+    ("darkseagreen", 0x8fbc8f),
+    ("darkslateblue", 0x483d8b),
+    ("darkslategray", 0x2f4f4f),
+    ("darkslategrey", 0x2f4f4f),
+    ("darkturquoise", 0x00ced1),
+    ("darkviolet", 0x9400d3),
+    ("deeppink", 0xff1493),
+    ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969),
+    ("dimgrey", 0x696969),
+    ("dodgerblue", 0x1e90ff),
+    ("firebrick", 0xb22222),
+    ("floralwhite", 0xfffaf0),
+    ("forestgreen", 0x228b22),
+    ("fuchsia", 0xff00ff),
+    ("gainsboro", 0xdcdcdc),
+    ("ghostwhite", 0xf8f8ff),
+    ("gold", 0xffd700),
+    ("goldenrod", 0xdaa520),
+    ("gray", 0x808080),
+    ("green", 0x008000),
+    ("greenyellow", 0xadff2f),
+    ("grey", 0x808080),
+    ("honeydew", 0xf0fff0),
+    ("hotpink", 0xff69b4),
+    ("indianred", 0xcd5c5c),
+    ("indigo", 0x4b0082),
+    ("ivory", 0xfffff0),
+    ("khaki", 0xf0e68c),
+    ("lavender", 0xe6e6fa),
+    ("lavenderblush", 0xfff0f5),
+    ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd),
+    ("lightblue", 0xadd8e6),
+    ("lightcoral", 0xf08080),
+    ("lightcyan", 0xe0ffff),
+    ("lightgoldenrodyellow", 0xfafad2),
+    ("lightgray", 0xd3d3d3),
+    ("lightgreen", 0x90ee90),
+    ("lightgrey", 0xd3d3d3),
+    ("lightpink", 0xffb6c1),
+    ("lightsalmon", 0xffa07a),
+    ("lightseagreen", 0x20b2aa),
+    ("lightskyblue", 0x87cefa),
+    ("lightslategray", 0x778899),
+    ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xb0c4de),
+    ("lightyellow", 0xffffe0),
+    ("lime", 0x00ff00),
+    ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6),
+    ("magenta", 0xff00ff),
+    ("maroon", 0x800000),
+    ("mediumaquamarine", 0x66cdaa),
+    ("mediumblue", 0x0000cd),
+    ("mediumorchid", 0xba55d3),
+    ("mediumpurple", 0x9370db),
+    ("mediumseagreen", 0x3cb371),
+    ("mediumslateblue", 0x7b68ee),
+    ("mediumspringgreen", 0x00fa9a),
+    ("mediumturquoise", 0x48d1cc),
+    ("mediumvioletred", 0xc71585),
+    ("midnightblue", 0x191970),
+    ("mintcream", 0xf5fffa),
+    ("mistyrose", 0xffe4e1),
+    ("moccasin", 0xffe4b5),
+    ("navajowhite", 0xffdead),
+    ("navy", 0x000080),
+    ("oldlace", 0xfdf5e6),
+    ("olive", 0x808000),
+    ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500),
+    ("orangered", 0xff4500),
+    ("orchid", 0xda70d6),
+    ("palegoldenrod", 0xeee8aa),
+    ("palegreen", 0x98fb98),
+    ("paleturquoise", 0xafeeee),
+    ("palevioletred", 0xdb7093),
+    ("papayawhip", 0xffefd5),
+    ("peachpuff", 0xffdab9),
+    ("peru", 0xcd853f),
+    ("pink", 0xffc0cb),
+    ("plum", 0xdda0dd),
+    ("powderblue", 0xb0e0e6),
+    ("purple", 0x800080),
+    ("rebeccapurple", 0x663399),
+    ("red", 0xff0000),
+    ("rosybrown", 0xbc8f8f),
+    ("royalblue", 0x4169e1),
+    ("saddlebrown", 0x8b4513),
+    ("salmon", 0xfa8072),
+    ("sandybrown", 0xf4a460),
+    ("seagreen", 0x2e8b57),
+    ("seashell", 0xfff5ee),
+    ("sienna", 0xa0522d),
+    ("silver", 0xc0c0c0),
+    ("skyblue", 0x87ceeb),
+    ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090),
+    ("slategrey", 0x708090),
+    ("snow", 0xfffafa),
+    ("springgreen", 0x00ff7f),
+    ("steelblue", 0x4682b4),
+    ("tan", 0xd2b48c),
+    ("teal", 0x008080),
+    ("thistle", 0xd8bfd8),
+    ("tomato", 0xff6347),
+    ("turquoise", 0x40e0d0),
+    ("violet", 0xee82ee),
+    ("wheat", 0xf5deb3),
+    ("white", 0xffffff),
+    ("whitesmoke", 0xf5f5f5),
+    ("yellow", 0xffff00),
+    ("yellowgreen", 0x9acd32),
+];
+
 pub fn parse(source: String) -> Stylesheet {
     let mut parser = Parser {
         pos: 0,
         input: source,
+        imports: Vec::new(),
     };
     Stylesheet {
         rules: parser.parse_rules(),
     }
 }
 
+/// Parse a list of declarations without a selector or braces, like the contents of a
+/// `style` attribute. The semicolon after the last declaration is optional.
+pub fn parse_declarations(source: &str) -> Vec<Declaration> {
+    let mut parser = Parser {
+        pos: 0,
+        input: source.to_string(),
+        imports: Vec::new(),
+    };
+    parser.parse_declaration_list()
+}
+
+/// Like `parse`, but resolves `@import` rules by calling `loader` with the imported
+/// URL. Imported rules come before the importing sheet's own rules, so they lose ties
+/// in the cascade. A sheet that is imported again, e.g. through a cycle back to the
+/// sheet at `url`, is skipped.
+pub fn parse_with_imports<F>(url: &str, source: String, loader: F) -> Stylesheet
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut visited = HashSet::from([url.to_string()]);
+    Stylesheet {
+        rules: parse_imported_rules(source, &loader, &mut visited),
+    }
+}
+
+fn parse_imported_rules(
+    source: String,
+    loader: &dyn Fn(&str) -> Option<String>,
+    visited: &mut HashSet<String>,
+) -> Vec<Rule> {
+    let mut parser = Parser {
+        pos: 0,
+        input: source,
+        imports: Vec::new(),
+    };
+    let own_rules = parser.parse_rules();
+
+    let mut rules = Vec::new();
+    for url in parser.imports {
+        if !visited.insert(url.clone()) {
+            continue;
+        }
+        if let Some(source) = loader(&url) {
+            rules.extend(parse_imported_rules(source, loader, visited));
+        }
+    }
+    rules.extend(own_rules);
+    rules
+}
+
 struct Parser {
     pos: usize,
     input: String,
+    // URLs of the `@import` rules seen so far, in order.
+    imports: Vec<String>,
 }
 
 impl Parser {
     fn next_char(&self) -> char {
+        debug_assert!(self.input.is_char_boundary(self.pos));
         self.input[self.pos..].chars().next().unwrap()
     }
 
+    fn starts_with(&self, s: &str) -> bool {
+        self.input[self.pos..].starts_with(s)
+    }
+
     fn eof(&self) -> bool {
         self.pos >= self.input.len()
     }
 
+    // Advance by the character's encoded length so `pos` always stays on a char
+    // boundary, even for a multi-byte character at the end of the input.
     fn consume_char(&mut self) -> char {
-        let mut iter = self.input[self.pos..].char_indices();
-        let (_, cur_char) = iter.next().unwrap();
-        let (next_pos, _) = iter.next().unwrap_or((1, ' '));
-        self.pos += next_pos;
+        let cur_char = self.next_char();
+        self.pos += cur_char.len_utf8();
         return cur_char;
     }
 
@@ -111,11 +910,51 @@ impl Parser {
             if self.eof() {
                 break;
             }
+            if self.next_char() == '@' {
+                self.parse_at_rule();
+                continue;
+            }
             rules.push(self.parse_rule())
         }
         rules
     }
 
+    // `@import` URLs are recorded; every other at-rule is discarded.
+    fn parse_at_rule(&mut self) {
+        assert_eq!(self.consume_char(), '@');
+        let name = self.parse_identifier();
+        self.consume_whitespace();
+        if name.eq_ignore_ascii_case("import") {
+            let url = match self.next_char() {
+                '"' | '\'' => Some(self.parse_string()),
+                _ if self.starts_with("url(") => Some(self.parse_url()),
+                _ => None,
+            };
+            self.imports.extend(url);
+        }
+        self.skip_at_rule();
+    }
+
+    // Skip the rest of an at-rule: either a statement ending in `;` or a block, whose
+    // nested braces must balance. Braces and semicolons inside strings don't count.
+    fn skip_at_rule(&mut self) {
+        let mut depth = 0;
+        while !self.eof() {
+            if matches!(self.next_char(), '"' | '\'') {
+                self.parse_string();
+                continue;
+            }
+            match self.consume_char() {
+                ';' if depth == 0 => return,
+                '{' => depth += 1,
+                // 対応する`{`のない`}`でも、そこで規則を終える
+                '}' if depth <= 1 => return,
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+
     fn parse_rule(&mut self) -> Rule {
         Rule {
             selectors: self.parse_selectors(),
@@ -152,67 +991,334 @@ impl Parser {
                 self.consume_char();
                 break;
             }
-            declarations.push(self.parse_declaration())
+            declarations.extend(self.parse_declaration())
         }
         return declarations;
     }
 
-    fn parse_declaration(&mut self) -> Declaration {
+    // 入力の終わりまで宣言を読む。空の宣言(`;;`)は読み飛ばす
+    fn parse_declaration_list(&mut self) -> Vec<Declaration> {
+        let mut declarations = Vec::new();
+        loop {
+            self.consume_whitespace();
+            if self.eof() {
+                break;
+            }
+            if self.next_char() == ';' {
+                self.consume_char();
+                continue;
+            }
+            declarations.extend(self.parse_declaration())
+        }
+        declarations
+    }
+
+    // 一括指定のプロパティは個別のプロパティの宣言に分けて返す
+    fn parse_declaration(&mut self) -> Vec<Declaration> {
         let property_name = self.parse_identifier();
         self.consume_whitespace();
         assert_eq!(self.consume_char(), ':');
         self.consume_whitespace();
-        let value = self.parse_value();
+        let declarations = match &*property_name {
+            "border" | "border-top" | "border-right" | "border-bottom" | "border-left"
+            | "outline" => expand_border(&property_name, self.parse_value_list()),
+            // 辺ごとの値が優先されるので、四辺すべての値として宣言する
+            "border-width" | "border-style" | "border-color" => {
+                let longhand = &property_name["border-".len()..];
+                border_sides(longhand, self.parse_value())
+            }
+            "font" => self.parse_font(),
+            "background" => expand_background(self.parse_value_list()),
+            // カスタムプロパティは、どこで使われるか分からないので値をすべて残す
+            name if name.starts_with("--") => {
+                let mut values = self.parse_value_list();
+                let value = if values.len() == 1 {
+                    values.remove(0)
+                } else {
+                    Value::List(values)
+                };
+                vec![Declaration {
+                    name: property_name,
+                    value: value,
+                }]
+            }
+            _ => vec![Declaration {
+                name: property_name,
+                value: self.parse_value(),
+            }],
+        };
         self.consume_whitespace();
+        // 最後の宣言の後の`;`は省略できる
+        if self.eof() || self.next_char() == '}' {
+            return declarations;
+        }
         assert_eq!(self.consume_char(), ';');
 
-        return Declaration {
-            name: property_name,
-            value: value,
+        return declarations;
+    }
+
+    // `font: [style] [weight] size[/line-height] family[, family...]`。省略された値はnormalにする。
+    // font-familyは一つの値しか持てないので、最初の候補だけを使う
+    fn parse_font(&mut self) -> Vec<Declaration> {
+        let mut font_style = Value::keyword("normal");
+        let mut font_weight = Value::keyword("normal");
+        let font_size = loop {
+            self.consume_whitespace();
+            // 大きさがなければ宣言全体が無効になる
+            if self.at_value_end() {
+                return Vec::new();
+            }
+            match self.parse_value() {
+                Value::Keyword(ref k) if matches!(&**k, "italic" | "oblique") => {
+                    font_style = Value::keyword(k)
+                }
+                Value::Keyword(ref k) if matches!(&**k, "bold" | "bolder" | "lighter") => {
+                    font_weight = Value::keyword(k)
+                }
+                weight @ Value::Number(_) => font_weight = weight,
+                Value::Keyword(ref k) if k == "normal" => {}
+                size if is_font_size(&size) => break size,
+                _ => {
+                    self.consume_while(|c| c != ';' && c != '}');
+                    return Vec::new();
+                }
+            }
+        };
+        let line_height = if self.starts_with("/") {
+            self.consume_char();
+            self.consume_whitespace();
+            self.parse_value()
+        } else {
+            Value::keyword("normal")
         };
+        self.consume_whitespace();
+        // font-familyも省略できない
+        if self.at_value_end() {
+            return Vec::new();
+        }
+        let font_family = self.parse_value();
+        self.consume_while(|c| c != ';' && c != '}');
+
+        [
+            ("font-style", font_style),
+            ("font-weight", font_weight),
+            ("font-size", font_size),
+            ("line-height", line_height),
+            ("font-family", font_family),
+        ]
+        .into_iter()
+        .map(|(name, value)| Declaration {
+            name: name.to_string(),
+            value: value,
+        })
+        .collect()
+    }
+
+    // 宣言の値の終わりに達したかどうか
+    fn at_value_end(&self) -> bool {
+        self.eof() || matches!(self.next_char(), ';' | '}')
+    }
+
+    // calc()の中身: 和と差は積と商より優先順位が低い
+    fn parse_calc_sum(&mut self) -> CalcExpr {
+        let mut expr = self.parse_calc_product();
+        loop {
+            self.consume_whitespace();
+            let op = match self.next_char() {
+                '+' => CalcExpr::Add,
+                '-' => CalcExpr::Sub,
+                _ => return expr,
+            };
+            self.consume_char();
+            self.consume_whitespace();
+            expr = op(Box::new(expr), Box::new(self.parse_calc_product()));
+        }
+    }
+
+    fn parse_calc_product(&mut self) -> CalcExpr {
+        let mut expr = self.parse_calc_operand();
+        loop {
+            self.consume_whitespace();
+            let op = match self.next_char() {
+                '*' => CalcExpr::Mul,
+                '/' => CalcExpr::Div,
+                _ => return expr,
+            };
+            self.consume_char();
+            self.consume_whitespace();
+            expr = op(Box::new(expr), Box::new(self.parse_calc_operand()));
+        }
+    }
+
+    // 括弧で囲まれた式か、長さや数値。入れ子のcalc()は括弧と同じように扱う
+    fn parse_calc_operand(&mut self) -> CalcExpr {
+        if self.starts_with("calc(") {
+            self.pos += "calc".len();
+        }
+        if self.next_char() == '(' {
+            self.consume_char();
+            self.consume_whitespace();
+            let expr = self.parse_calc_sum();
+            self.consume_whitespace();
+            assert_eq!(self.consume_char(), ')');
+            return expr;
+        }
+        assert!(self.starts_number(), "Expected a number in calc()");
+        CalcExpr::Value(self.parse_length())
+    }
+
+    // 空白で区切られた値を`;`の手前まで読む
+    fn parse_value_list(&mut self) -> Vec<Value> {
+        let mut values = Vec::new();
+        loop {
+            self.consume_whitespace();
+            if self.eof() || matches!(self.next_char(), ';' | '}') {
+                break;
+            }
+            let start = self.pos;
+            values.push(self.parse_value());
+            if self.pos == start {
+                panic!("Unexpected character {} in value", self.next_char());
+            }
+        }
+        values
     }
 
     fn parse_value(&mut self) -> Value {
         match self.next_char() {
-            '0'..='9' => self.parse_length(),
+            _ if self.starts_number() => self.parse_length(),
             '#' => self.parse_color(),
-            _ => Value::Keyword(self.parse_identifier()),
+            '"' | '\'' => Value::Str(self.parse_string()),
+            _ if self.starts_with("url(") => Value::Url(self.parse_url()),
+            _ if self.starts_with("var(") => self.parse_var(),
+            _ if self.starts_with("calc(") => {
+                self.pos += "calc".len();
+                Value::Calc(Box::new(self.parse_calc_operand()))
+            }
+            _ => {
+                let identifier = self.parse_identifier();
+                match Color::from_named(&identifier) {
+                    Some(color) => Value::ColorValue(color),
+                    None => Value::keyword(&identifier),
+                }
+            }
         }
     }
 
     fn parse_length(&mut self) -> Value {
-        Value::Length(self.parse_float(), self.parse_unit())
+        let f = self.parse_float();
+        if !self.eof() && self.next_char() == '%' {
+            self.consume_char();
+            return Value::Length(f, Unit::Percent);
+        }
+        // A bare number without a unit, e.g. `opacity: 0.5`.
+        if self.eof() || !valid_identifier_char(self.next_char()) {
+            return Value::Number(f);
+        }
+        Value::Length(f, self.parse_unit())
     }
 
+    // Numbers may be signed, start with a `.`, and carry an exponent, e.g. `-1.5e2`.
     fn parse_float(&mut self) -> f32 {
-        let s = self.consume_while(|c| match c {
+        let mut s = String::new();
+        if matches!(self.next_char(), '-' | '+') {
+            s.push(self.consume_char());
+        }
+        s.push_str(&self.consume_while(|c| match c {
             '0'..='9' | '.' => true,
             _ => false,
-        });
-        s.parse().unwrap()
+        }));
+        // Only read an exponent when digits follow, so `1em` keeps its unit.
+        if self.starts_exponent() {
+            s.push(self.consume_char());
+            if matches!(self.next_char(), '-' | '+') {
+                s.push(self.consume_char());
+            }
+            s.push_str(&self.consume_while(|c| c.is_ascii_digit()));
+        }
+        match s.parse() {
+            Ok(f) => f,
+            Err(_) => panic!("Invalid number {}", s),
+        }
+    }
+
+    fn starts_number(&self) -> bool {
+        let mut chars = self.input[self.pos..].chars().peekable();
+        chars.next_if(|&c| c == '-' || c == '+');
+        chars.next_if_eq(&'.');
+        matches!(chars.next(), Some('0'..='9'))
+    }
+
+    fn starts_exponent(&self) -> bool {
+        let mut chars = self.input[self.pos..].chars().peekable();
+        if chars.next_if(|&c| c == 'e' || c == 'E').is_none() {
+            return false;
+        }
+        chars.next_if(|&c| c == '-' || c == '+');
+        matches!(chars.next(), Some('0'..='9'))
     }
 
     fn parse_unit(&mut self) -> Unit {
         match &*self.parse_identifier().to_ascii_lowercase() {
             "px" => Unit::Px,
+            "em" => Unit::Em,
+            "rem" => Unit::Rem,
+            "ex" => Unit::Ex,
+            "ch" => Unit::Ch,
             _ => panic!("unrecognized unit"),
         }
     }
 
     fn parse_color(&mut self) -> Value {
         assert_eq!(self.consume_char(), '#');
-        Value::ColorValue(Color {
-            r: self.parse_hex_pair(),
-            g: self.parse_hex_pair(),
-            b: self.parse_hex_pair(),
-            a: 255,
-        })
+        let hex = self.consume_while(|c| c.is_ascii_hexdigit());
+        match Color::from_hex(&format!("#{}", hex)) {
+            Some(color) => Value::ColorValue(color),
+            None => panic!("Invalid color #{}", hex),
+        }
+    }
+
+    // `var(--name)`または`var(--name, fallback)`
+    fn parse_var(&mut self) -> Value {
+        assert!(self.starts_with("var("));
+        self.pos += "var(".len();
+        self.consume_whitespace();
+        let name = self.parse_identifier();
+        self.consume_whitespace();
+        let fallback = if self.starts_with(",") {
+            self.consume_char();
+            self.consume_whitespace();
+            let fallback = self.parse_value();
+            self.consume_whitespace();
+            Some(Box::new(fallback))
+        } else {
+            None
+        };
+        assert!(!self.eof(), "Unterminated var()");
+        assert_eq!(self.consume_char(), ')');
+        Value::Var(name, fallback)
+    }
+
+    // `url(...)` with a quoted or unquoted URL.
+    fn parse_url(&mut self) -> String {
+        assert!(self.starts_with("url("));
+        self.pos += "url(".len();
+        self.consume_whitespace();
+        let url = match self.next_char() {
+            '"' | '\'' => self.parse_string(),
+            _ => self.consume_while(|c| c != ')' && !c.is_whitespace()),
+        };
+        self.consume_whitespace();
+        assert!(!self.eof(), "Unterminated url()");
+        assert_eq!(self.consume_char(), ')');
+        url
     }
 
-    fn parse_hex_pair(&mut self) -> u8 {
-        let s = &self.input[self.pos..self.pos + 2];
-        self.pos += 2;
-        u8::from_str_radix(s, 16).unwrap()
+    fn parse_string(&mut self) -> String {
+        let quote = self.consume_char();
+        let value = self.consume_while(|c| c != quote);
+        assert_eq!(self.consume_char(), quote);
+        value
     }
 
     fn parse_identifier(&mut self) -> String {
@@ -224,6 +1330,9 @@ impl Parser {
             tag_name: None,
             id: None,
             class: Vec::new(),
+            pseudo_classes: Vec::new(),
+            pseudo_element: None,
+            specificity: Specificity::default(),
         };
 
         while !self.eof() {
@@ -239,6 +1348,30 @@ impl Parser {
                 '*' => {
                     self.consume_char();
                 }
+                ':' => {
+                    self.consume_char();
+                    // `::before` and the legacy `:before` are both accepted.
+                    if self.next_char() == ':' {
+                        self.consume_char();
+                    }
+                    match &*self.parse_identifier() {
+                        "before" => selector.pseudo_element = Some(PseudoElement::Before),
+                        "after" => selector.pseudo_element = Some(PseudoElement::After),
+                        "hover" => selector.pseudo_classes.push(PseudoClass::Hover),
+                        "root" => selector.pseudo_classes.push(PseudoClass::Root),
+                        "not" => {
+                            assert_eq!(self.consume_char(), '(');
+                            self.consume_whitespace();
+                            let inner = self.parse_simple_selector();
+                            self.consume_whitespace();
+                            assert_eq!(self.consume_char(), ')');
+                            selector
+                                .pseudo_classes
+                                .push(PseudoClass::Not(Box::new(inner)));
+                        }
+                        name => panic!("Unsupported pseudo selector {}", name),
+                    }
+                }
                 c if valid_identifier_char(c) => {
                     selector.tag_name = Some(self.parse_identifier());
                 }
@@ -246,25 +1379,678 @@ impl Parser {
             }
         }
 
+        selector.specificity = selector.compute_specificity();
         return selector;
     }
 }
 
-pub type Specificity = (usize, usize, usize);
+/// Selector specificity. Comparison is lexicographic: the id count decides first,
+/// then the class count, then the tag count, so a single id outweighs any number
+/// of classes and a single class outweighs any number of tags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Specificity {
+    pub ids: usize,
+    pub classes: usize,
+    pub tags: usize,
+}
 
 impl Selector {
     pub fn specificity(&self) -> Specificity {
         let Selector::Simple(ref simple) = *self;
-        let a = simple.id.iter().count();
-        let b = simple.class.len();
-        let c = simple.tag_name.iter().count();
-        return (a, b, c);
+        simple.specificity
+    }
+
+    pub fn to_css(&self) -> String {
+        let Selector::Simple(ref simple) = *self;
+        simple.to_css()
     }
 }
 
+impl SimpleSelector {
+    pub fn to_css(&self) -> String {
+        let mut css = self.tag_name.clone().unwrap_or_default();
+        if let Some(ref id) = self.id {
+            css.push_str(&format!("#{}", id));
+        }
+        for class in &self.class {
+            css.push_str(&format!(".{}", class));
+        }
+        for pseudo_class in &self.pseudo_classes {
+            match *pseudo_class {
+                PseudoClass::Hover => css.push_str(":hover"),
+                PseudoClass::Root => css.push_str(":root"),
+                PseudoClass::Not(ref inner) => css.push_str(&format!(":not({})", inner.to_css())),
+            }
+        }
+        // 何も指定されていなければ全称セレクター
+        if css.is_empty() {
+            css.push('*');
+        }
+        match self.pseudo_element {
+            Some(PseudoElement::Before) => css.push_str("::before"),
+            Some(PseudoElement::After) => css.push_str("::after"),
+            None => {}
+        }
+        css
+    }
+
+    /// Count the ids, classes (and pseudo-classes) and tags (and pseudo-elements) in
+    /// the selector, each separately: `div#id.a.b` is (1, 2, 1), which outranks
+    /// `.a.b.c` at (0, 3, 0). `*` counts for nothing.
+    pub fn compute_specificity(&self) -> Specificity {
+        let mut a = self.id.iter().count();
+        let mut b = self.class.len();
+        let mut c = self.tag_name.iter().count() + self.pseudo_element.iter().count();
+        for pseudo_class in &self.pseudo_classes {
+            match *pseudo_class {
+                PseudoClass::Hover | PseudoClass::Root => b += 1,
+                // `:not()` counts as its argument does.
+                PseudoClass::Not(ref inner) => {
+                    let inner = inner.compute_specificity();
+                    a += inner.ids;
+                    b += inner.classes;
+                    c += inner.tags;
+                }
+            }
+        }
+        return Specificity {
+            ids: a,
+            classes: b,
+            tags: c,
+        };
+    }
+}
+
+// `border`や`border-top`、`outline`の値を、順番に関係なく太さ・スタイル・色の個別のプロパティに振り分ける
+fn expand_border(name: &str, values: Vec<Value>) -> Vec<Declaration> {
+    // 省略された値は初期値に戻す
+    let mut width = Value::keyword("medium");
+    let mut style = Value::keyword("none");
+    let mut color = Value::keyword("currentcolor");
+    for value in values {
+        match value {
+            Value::Length(..) | Value::Number(_) => width = value,
+            Value::Keyword(ref k) if matches!(&**k, "thin" | "medium" | "thick") => width = value,
+            Value::Keyword(ref k) if is_border_style(k) => style = value,
+            _ => color = value,
+        }
+    }
+    let longhands = [("width", width), ("style", style), ("color", color)];
+    if name != "border" {
+        return longhands
+            .into_iter()
+            .map(|(longhand, value)| Declaration {
+                name: format!("{}-{}", name, longhand),
+                value: value,
+            })
+            .collect();
+    }
+    // borderは四辺すべてに広げる
+    longhands
+        .into_iter()
+        .flat_map(|(longhand, value)| border_sides(longhand, value))
+        .collect()
+}
+
+// `border-{side}-{longhand}`の宣言を四辺分作る
+fn border_sides(longhand: &str, value: Value) -> Vec<Declaration> {
+    ["top", "right", "bottom", "left"]
+        .iter()
+        .map(|side| Declaration {
+            name: format!("border-{}-{}", side, longhand),
+            value: value.clone(),
+        })
+        .collect()
+}
+
+// `background`の値を背景色と背景画像に振り分ける。省略された方は初期値にする。
+// 位置や繰り返しの指定はまだ扱わないので捨てる
+fn expand_background(values: Vec<Value>) -> Vec<Declaration> {
+    let mut color = Value::keyword("transparent");
+    let mut image = Value::keyword("none");
+    for value in values {
+        match value {
+            Value::Url(_) => image = value,
+            Value::Keyword(ref k) if k == "none" => image = value,
+            Value::ColorValue(_) => color = value,
+            Value::Keyword(ref k) if !is_background_layout_keyword(k) => color = value,
+            _ => {}
+        }
+    }
+    vec![
+        Declaration {
+            name: "background-color".to_string(),
+            value: color,
+        },
+        Declaration {
+            name: "background-image".to_string(),
+            value: image,
+        },
+    ]
+}
+
+fn is_background_layout_keyword(keyword: &str) -> bool {
+    matches!(
+        keyword,
+        "repeat"
+            | "repeat-x"
+            | "repeat-y"
+            | "no-repeat"
+            | "space"
+            | "round"
+            | "center"
+            | "top"
+            | "bottom"
+            | "left"
+            | "right"
+            | "scroll"
+            | "fixed"
+            | "local"
+    )
+}
+
+fn is_font_size(value: &Value) -> bool {
+    match *value {
+        Value::Length(..) | Value::Calc(_) => true,
+        Value::Keyword(ref k) => matches!(
+            &**k,
+            "xx-small"
+                | "x-small"
+                | "small"
+                | "medium"
+                | "large"
+                | "x-large"
+                | "xx-large"
+                | "larger"
+                | "smaller"
+        ),
+        _ => false,
+    }
+}
+
+fn is_border_style(keyword: &str) -> bool {
+    matches!(
+        keyword,
+        "none"
+            | "hidden"
+            | "dotted"
+            | "dashed"
+            | "solid"
+            | "double"
+            | "groove"
+            | "ridge"
+            | "inset"
+            | "outset"
+    )
+}
+
 fn valid_identifier_char(c: char) -> bool {
     match c {
         'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => true,
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn declarations(source: &str) -> Vec<Declaration> {
+        parse(format!("a {{ {} }}", source))
+            .rules
+            .remove(0)
+            .declarations
+    }
+
+    fn value_of(declarations: &[Declaration], name: &str) -> Value {
+        declarations
+            .iter()
+            .find(|declaration| declaration.name == name)
+            .map(|declaration| declaration.value.clone())
+            .unwrap_or_else(|| panic!("no {} declaration", name))
+    }
+
+    #[test]
+    fn cached_specificity_matches_a_fresh_computation() {
+        let source = "div {} #a {} .b {} div#a {} div.b.c {} #a.b {} p#a.b.c {}";
+        let stylesheet = parse(source.to_string());
+        assert_eq!(stylesheet.rules.len(), 7);
+        for rule in &stylesheet.rules {
+            let Selector::Simple(ref simple) = rule.selectors[0];
+            assert_ne!(simple.specificity, Specificity::default());
+            assert_eq!(simple.specificity, simple.compute_specificity());
+        }
+    }
+
+    #[test]
+    fn interned_keywords_compare_by_name() {
+        let interned = Value::keyword("block");
+        assert!(matches!(interned, Value::Keyword(Cow::Borrowed(_))));
+        assert_eq!(interned, Value::Keyword(Cow::Owned("block".to_string())));
+        assert_eq!(
+            Value::keyword("no-such-keyword"),
+            Value::keyword("no-such-keyword")
+        );
+        assert_ne!(Value::keyword("block"), Value::keyword("inline"));
+        let declarations = declarations("display: block;");
+        assert_eq!(value_of(&declarations, "display"), interned);
+    }
+
+    #[test]
+    fn specificity_categories_never_carry_over() {
+        let id = Specificity {
+            ids: 1,
+            classes: 0,
+            tags: 0,
+        };
+        let many_classes = Specificity {
+            ids: 0,
+            classes: 300,
+            tags: 0,
+        };
+        let class = Specificity {
+            ids: 0,
+            classes: 1,
+            tags: 0,
+        };
+        let many_tags = Specificity {
+            ids: 0,
+            classes: 0,
+            tags: 300,
+        };
+        assert!(id > many_classes);
+        assert!(class > many_tags);
+
+        let classes: String = (0..20).map(|i| format!(".c{}", i)).collect();
+        let stylesheet = parse(format!("#a {{}} {} {{}}", classes));
+        let id = stylesheet.rules[0].selectors[0].specificity();
+        let many_classes = stylesheet.rules[1].selectors[0].specificity();
+        assert_eq!(many_classes.classes, 20);
+        assert!(id > many_classes);
+    }
+
+    #[test]
+    fn unitless_numbers_and_lengths() {
+        let declarations = declarations("line-height: 1.5; width: 1.5px;");
+        assert_eq!(value_of(&declarations, "line-height"), Value::Number(1.5));
+        assert_eq!(
+            value_of(&declarations, "width"),
+            Value::Length(1.5, Unit::Px)
+        );
+    }
+
+    #[test]
+    fn signed_and_decimal_numbers() {
+        let declarations =
+            declarations("margin-left: -10px; width: .5em; height: 0; opacity: 1e-1;");
+        assert_eq!(
+            value_of(&declarations, "margin-left"),
+            Value::Length(-10.0, Unit::Px)
+        );
+        assert_eq!(
+            value_of(&declarations, "width"),
+            Value::Length(0.5, Unit::Em)
+        );
+        assert_eq!(value_of(&declarations, "height"), Value::Number(0.0));
+        assert_eq!(value_of(&declarations, "opacity"), Value::Number(0.1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid number 1.2.3")]
+    fn malformed_number_is_rejected() {
+        declarations("width: 1.2.3px;");
+    }
+
+    #[test]
+    fn unknown_at_rules_are_skipped() {
+        let tag_name = |stylesheet: &Stylesheet| match stylesheet.rules[0].selectors[0] {
+            Selector::Simple(ref simple) => simple.tag_name.clone(),
+        };
+
+        let stylesheet = parse("@import url(x.css); p{color:#ff0000;}".to_string());
+        assert_eq!(stylesheet.rules.len(), 1);
+        assert_eq!(tag_name(&stylesheet), Some("p".to_string()));
+
+        let source =
+            "@media screen { a { color: #0000ff; } } @charset \"utf-8\"; p{color:#ff0000;}";
+        let stylesheet = parse(source.to_string());
+        assert_eq!(stylesheet.rules.len(), 1);
+        assert_eq!(tag_name(&stylesheet), Some("p".to_string()));
+
+        let stylesheet = parse("@foo } p{color:red} q{color:blue}".to_string());
+        assert_eq!(stylesheet.rules.len(), 2);
+        assert_eq!(tag_name(&stylesheet), Some("p".to_string()));
+        assert_eq!(
+            stylesheet.rules[1].declarations[0].value,
+            Value::ColorValue(Color::from_named("blue").unwrap())
+        );
+    }
+
+    #[test]
+    fn imported_rules_come_first() {
+        let loader = |url: &str| match url {
+            "base.css" => Some("@import \"main.css\"; p { color: #ff0000; }".to_string()),
+            _ => None,
+        };
+        let source = "@import \"base.css\"; p { color: #0000ff; }".to_string();
+        let stylesheet = parse_with_imports("main.css", source, loader);
+        let colors: Vec<&Value> = stylesheet
+            .rules
+            .iter()
+            .map(|rule| &rule.declarations[0].value)
+            .collect();
+        assert_eq!(
+            colors,
+            [
+                &Value::ColorValue(Color::from_hex("#ff0000").unwrap()),
+                &Value::ColorValue(Color::from_hex("#0000ff").unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn url_values() {
+        let declarations =
+            declarations("background-image: url(a.png); list-style-image: url( \"b.png\" );");
+        assert_eq!(
+            value_of(&declarations, "background-image"),
+            Value::Url("a.png".to_string())
+        );
+        assert_eq!(
+            value_of(&declarations, "list-style-image"),
+            Value::Url("b.png".to_string())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Unterminated url()")]
+    fn unterminated_url_is_rejected() {
+        parse("a { background-image: url(a.png".to_string());
+    }
+
+    #[test]
+    fn compound_selector_specificity() {
+        let stylesheet = parse("div#id.a.b {} .a.b.c {}".to_string());
+        let compound = stylesheet.rules[0].selectors[0].specificity();
+        let classes = stylesheet.rules[1].selectors[0].specificity();
+        assert_eq!(
+            compound,
+            Specificity {
+                ids: 1,
+                classes: 2,
+                tags: 1
+            }
+        );
+        assert_eq!(
+            classes,
+            Specificity {
+                ids: 0,
+                classes: 3,
+                tags: 0
+            }
+        );
+        assert!(compound > classes);
+    }
+
+    #[test]
+    fn validate_reports_unknown_properties() {
+        let typo = parse("p { colr: red; }".to_string());
+        assert_eq!(
+            typo.validate(),
+            [Warning::UnknownProperty("colr".to_string())]
+        );
+        let valid = parse("p { color: red; }".to_string());
+        assert!(valid.validate().is_empty());
+    }
+
+    #[test]
+    fn validate_accepts_only_keywords_of_the_property() {
+        let invalid = parse("p { width: bogus; color: blah; margin-top: red; }".to_string());
+        let names: Vec<String> = invalid
+            .validate()
+            .into_iter()
+            .map(|warning| match warning {
+                Warning::InvalidValue(name, _) => name,
+                Warning::UnknownProperty(name) => panic!("unexpected unknown property {}", name),
+            })
+            .collect();
+        assert_eq!(names, ["width", "color", "margin-top"]);
+
+        let valid = parse(
+            "p { width: auto; color: currentColor; margin-top: inherit; display: flex; \
+             border-top-width: thick; font-family: Helvetica; }"
+                .to_string(),
+        );
+        assert_eq!(valid.validate(), []);
+    }
+
+    #[test]
+    fn to_css_round_trips() {
+        let source = "h1, .a#b { color: #ff000080; margin: 0; width: 50%; } \
+                      p { background-color: transparent; font-family: \"serif\"; } \
+                      q { color: #ff000000; }";
+        let stylesheet = parse(source.to_string());
+        let css = stylesheet.to_css();
+        let reparsed = parse(css.clone());
+        assert_eq!(reparsed.to_css(), css);
+        assert_eq!(
+            reparsed.rules[0].declarations[0].value,
+            Value::ColorValue(Color {
+                r: 255,
+                g: 0,
+                b: 0,
+                a: 128
+            })
+        );
+        assert!(css.contains("background-color: transparent;"));
+        assert_eq!(
+            reparsed.rules[2].declarations[0].value,
+            Value::ColorValue(Color {
+                r: 255,
+                g: 0,
+                b: 0,
+                a: 0
+            })
+        );
+    }
+
+    #[test]
+    fn border_shorthand_sets_every_side() {
+        let border = declarations("border: solid 2px #000000;");
+        assert_eq!(border.len(), 12);
+        for side in ["top", "right", "bottom", "left"] {
+            let name = |longhand: &str| format!("border-{}-{}", side, longhand);
+            assert_eq!(
+                value_of(&border, &name("width")),
+                Value::Length(2.0, Unit::Px)
+            );
+            assert_eq!(value_of(&border, &name("style")), Value::keyword("solid"));
+            assert_eq!(
+                value_of(&border, &name("color")),
+                Value::ColorValue(Color::from_hex("#000000").unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn border_shorthand_resets_omitted_values() {
+        let border = declarations("border-top: dotted;");
+        assert_eq!(
+            value_of(&border, "border-top-width"),
+            Value::keyword("medium")
+        );
+        assert_eq!(
+            value_of(&border, "border-top-color"),
+            Value::keyword("currentcolor")
+        );
+
+        let outline = declarations("outline: 1px red;");
+        assert_eq!(value_of(&outline, "outline-style"), Value::keyword("none"));
+    }
+
+    #[test]
+    fn font_shorthand() {
+        let font = declarations("font: 16px serif;");
+        assert_eq!(value_of(&font, "font-size"), Value::Length(16.0, Unit::Px));
+        assert_eq!(value_of(&font, "font-family"), Value::keyword("serif"));
+        assert_eq!(value_of(&font, "font-weight"), Value::keyword("normal"));
+        assert_eq!(value_of(&font, "font-style"), Value::keyword("normal"));
+
+        let font = declarations("font: italic bold 12px/1.5 sans-serif;");
+        assert_eq!(value_of(&font, "font-style"), Value::keyword("italic"));
+        assert_eq!(value_of(&font, "font-weight"), Value::keyword("bold"));
+        assert_eq!(value_of(&font, "line-height"), Value::Number(1.5));
+    }
+
+    #[test]
+    fn font_shorthand_without_size_or_family_is_dropped() {
+        assert!(declarations("font: 12px;").is_empty());
+        assert!(declarations("font: bold;").is_empty());
+        assert!(declarations("font: bold serif; color: red;").len() == 1);
+    }
+
+    const WHITE: Color = Color {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 255,
+    };
+
+    #[test]
+    fn short_hex_colors() {
+        assert_eq!(Color::from_hex("#fff"), Some(WHITE));
+        assert_eq!(
+            Color::from_hex("#f008"),
+            Some(Color {
+                r: 255,
+                g: 0,
+                b: 0,
+                a: 0x88
+            })
+        );
+        assert_eq!(Color::from_hex("#ffff"), Some(WHITE));
+        assert_eq!(Color::from_hex("#ff"), None);
+    }
+
+    #[test]
+    fn background_shorthand() {
+        let background = declarations("background: #fff;");
+        assert_eq!(
+            value_of(&background, "background-color"),
+            Value::ColorValue(WHITE)
+        );
+        assert_eq!(
+            value_of(&background, "background-image"),
+            Value::keyword("none")
+        );
+
+        let background = declarations("background: url(x.png);");
+        assert_eq!(
+            value_of(&background, "background-image"),
+            Value::Url("x.png".to_string())
+        );
+        assert_eq!(
+            value_of(&background, "background-color"),
+            Value::keyword("transparent")
+        );
+
+        let background = declarations("background: #fff url(x.png) no-repeat;");
+        assert_eq!(
+            value_of(&background, "background-color"),
+            Value::ColorValue(WHITE)
+        );
+        assert_eq!(
+            value_of(&background, "background-image"),
+            Value::Url("x.png".to_string())
+        );
+
+        let background = declarations("background: #ff0000;");
+        assert_eq!(
+            value_of(&background, "background-color"),
+            Value::ColorValue(Color::from_hex("#ff0000").unwrap())
+        );
+    }
+
+    #[test]
+    fn named_colors() {
+        let hex = |s| Color::from_hex(s).unwrap();
+        assert_eq!(Color::from_named("rebeccapurple"), Some(hex("#663399")));
+        assert_eq!(Color::from_named("coral"), Some(hex("#ff7f50")));
+        assert_eq!(Color::from_named("DodgerBlue"), Some(hex("#1e90ff")));
+        assert_eq!(Color::from_named("papayawhip"), Some(hex("#ffefd5")));
+        assert_eq!(Color::from_named("bold"), None);
+
+        let declarations = declarations("color: coral; font-weight: bold;");
+        assert_eq!(
+            value_of(&declarations, "color"),
+            Value::ColorValue(hex("#ff7f50"))
+        );
+        assert_eq!(
+            value_of(&declarations, "font-weight"),
+            Value::keyword("bold")
+        );
+    }
+
+    #[test]
+    fn standalone_declaration_lists() {
+        let parsed = |source| {
+            parse_declarations(source)
+                .into_iter()
+                .map(|declaration| (declaration.name, declaration.value))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            (
+                "color".to_string(),
+                Value::ColorValue(Color::from_named("red").unwrap()),
+            ),
+            ("margin".to_string(), Value::Number(0.0)),
+        ];
+        assert_eq!(parsed("color: red; margin: 0"), expected);
+        assert_eq!(parsed("  color: red ;margin:0;  ; "), expected);
+        assert!(parsed("   ").is_empty());
+    }
+
+    #[test]
+    fn rule_accessors() {
+        let stylesheet = parse("p, .a { color: red; margin: 0; }".to_string());
+        let rule = &stylesheet.rules[0];
+        let names: Vec<&str> = rule
+            .declarations()
+            .iter()
+            .map(|declaration| declaration.name())
+            .collect();
+        assert_eq!(names, ["color", "margin"]);
+        assert_eq!(rule.declarations()[1].value(), &Value::Number(0.0));
+        // 詳細度の高い順に並ぶ
+        let selectors: Vec<String> = rule.selectors().iter().map(Selector::to_css).collect();
+        assert_eq!(selectors, [".a", "p"]);
+    }
+
+    #[test]
+    fn parse_var_with_fallback() {
+        let color = declarations("color: var(--c, var(--d, red));").remove(0);
+        assert_eq!(color.value.to_css(), "var(--c, var(--d, #ff0000))");
+        let custom = declarations("--c: 4px;").remove(0);
+        assert_eq!(custom.name, "--c");
+        assert_eq!(custom.value, Value::Length(4.0, Unit::Px));
+    }
+
+    #[test]
+    fn custom_properties_keep_every_value() {
+        let source = "p { --pad: 1px 2px; --font: bold \"serif\"; color: #000000; }";
+        let stylesheet = parse(source.to_string());
+        let declarations = &stylesheet.rules[0].declarations;
+        assert_eq!(declarations.len(), 3);
+        assert_eq!(
+            declarations[0].value,
+            Value::List(vec![
+                Value::Length(1.0, Unit::Px),
+                Value::Length(2.0, Unit::Px)
+            ])
+        );
+        assert_eq!(declarations[1].value.to_css(), "bold \"serif\"");
+        assert_eq!(parse(stylesheet.to_css()).to_css(), stylesheet.to_css());
+        assert!(stylesheet.validate().is_empty());
+    }
+}
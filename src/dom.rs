@@ -1,15 +1,19 @@
 use std::collections::{HashMap, HashSet};
-#[derive(Debug)]
+
+use crate::css::{self, Stylesheet};
+#[derive(Debug, PartialEq)]
 pub struct Node {
     pub children: Vec<Node>,
     pub node_type: NodeType,
 }
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum NodeType {
     Text(String),
     Element(ElementData),
+    // `html::Parser::set_keep_comments`を指定したときだけ作られる
+    Comment(String),
 }
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ElementData {
     pub tag_name: String,
     pub attributes: AttrMap,
@@ -17,6 +21,33 @@ pub struct ElementData {
 
 pub type AttrMap = HashMap<String, String>;
 
+/// Whether a document is laid out with the legacy quirks or by the standards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Quirks,
+    Standards,
+}
+
+/// A parsed document: its root node, and the mode it was parsed in.
+#[derive(Debug, PartialEq)]
+pub struct Document {
+    pub root: Node,
+    mode: Mode,
+}
+
+impl Document {
+    pub fn new(root: Node, mode: Mode) -> Document {
+        Document {
+            root: root,
+            mode: mode,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+}
+
 pub fn text(data: String) -> Node {
     Node {
         children: Vec::new(),
@@ -24,6 +55,13 @@ pub fn text(data: String) -> Node {
     }
 }
 
+pub fn comment(data: String) -> Node {
+    Node {
+        children: Vec::new(),
+        node_type: NodeType::Comment(data),
+    }
+}
+
 pub fn elem(name: String, attrs: AttrMap, children: Vec<Node>) -> Node {
     Node {
         children: children,
@@ -34,7 +72,37 @@ pub fn elem(name: String, attrs: AttrMap, children: Vec<Node>) -> Node {
     }
 }
 
+impl Node {
+    /// Concatenate the text of every descendant text node as-is, like the DOM's
+    /// `textContent`. Comments are left out.
+    pub fn text_content(&self) -> String {
+        let mut text = String::new();
+        self.collect_text(&mut text);
+        text
+    }
+
+    fn collect_text(&self, output: &mut String) {
+        match self.node_type {
+            NodeType::Text(ref text) => output.push_str(text),
+            NodeType::Element(_) => {
+                for child in &self.children {
+                    child.collect_text(output);
+                }
+            }
+            NodeType::Comment(_) => {}
+        }
+    }
+}
+
 impl ElementData {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|value| &**value)
+    }
+
+    pub fn attributes(&self) -> &AttrMap {
+        &self.attributes
+    }
+
     pub fn id(&self) -> Option<&String> {
         self.attributes.get("id")
     }
@@ -46,3 +114,305 @@ impl ElementData {
         }
     }
 }
+
+/// Collect the `href` of every `a` element, in document order.
+pub fn collect_links(root: &Node) -> Vec<String> {
+    let mut links = Vec::new();
+    collect_links_into(root, &mut links);
+    links
+}
+
+fn collect_links_into(node: &Node, links: &mut Vec<String>) {
+    if let NodeType::Element(ref elem) = node.node_type {
+        if elem.tag_name == "a" {
+            match elem.attr("href") {
+                Some(href) if !href.is_empty() => links.push(href.to_string()),
+                _ => {}
+            }
+        }
+    }
+    for child in &node.children {
+        collect_links_into(child, links);
+    }
+}
+
+/// Parse the contents of every `style` element into a stylesheet, in document order.
+pub fn extract_stylesheets(root: &Node) -> Vec<Stylesheet> {
+    let mut stylesheets = Vec::new();
+    extract_stylesheets_into(root, &mut stylesheets);
+    stylesheets
+}
+
+fn extract_stylesheets_into(node: &Node, stylesheets: &mut Vec<Stylesheet>) {
+    if let NodeType::Element(ref elem) = node.node_type {
+        if elem.tag_name == "style" {
+            let mut source = String::new();
+            for child in &node.children {
+                if let NodeType::Text(ref text) = child.node_type {
+                    source.push_str(text);
+                }
+            }
+            stylesheets.push(css::parse(source));
+            return;
+        }
+    }
+    for child in &node.children {
+        extract_stylesheets_into(child, stylesheets);
+    }
+}
+
+/// Return the text of `root` roughly as it would be rendered, like the DOM's
+/// `innerText`: whitespace runs collapse to one space, and block-level elements
+/// start and end a line. Script, style and other unrendered content is skipped.
+pub fn inner_text(root: &Node) -> String {
+    let mut lines = vec![String::new()];
+    collect_inner_text(root, &mut lines);
+    let lines: Vec<&str> = lines
+        .iter()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .collect();
+    lines.join("\n")
+}
+
+fn collect_inner_text(node: &Node, lines: &mut Vec<String>) {
+    let elem = match node.node_type {
+        NodeType::Text(ref text) => {
+            let line = lines.last_mut().unwrap();
+            for c in text.chars() {
+                if !c.is_whitespace() {
+                    line.push(c);
+                } else if !line.is_empty() && !line.ends_with(' ') {
+                    line.push(' ');
+                }
+            }
+            return;
+        }
+        NodeType::Comment(_) => return,
+        NodeType::Element(ref elem) => elem,
+    };
+    if matches!(
+        &*elem.tag_name,
+        "head" | "title" | "script" | "style" | "template"
+    ) {
+        return;
+    }
+
+    // ブロックレベルの要素の前後で改行する
+    let block = is_block_tag(&elem.tag_name);
+    if block {
+        lines.push(String::new());
+    }
+    for child in &node.children {
+        collect_inner_text(child, lines);
+    }
+    if block {
+        lines.push(String::new());
+    }
+}
+
+// ブラウザの既定のスタイルシートでdisplay: blockになる要素
+fn is_block_tag(tag_name: &str) -> bool {
+    matches!(
+        tag_name,
+        "html"
+            | "body"
+            | "div"
+            | "p"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "ul"
+            | "ol"
+            | "li"
+            | "dl"
+            | "dt"
+            | "dd"
+            | "pre"
+            | "blockquote"
+            | "section"
+            | "article"
+            | "header"
+            | "footer"
+            | "nav"
+            | "aside"
+            | "main"
+            | "form"
+            | "fieldset"
+            | "table"
+            | "tr"
+            | "hr"
+            | "address"
+            | "figure"
+            | "figcaption"
+    )
+}
+
+/// Serialize `root` as HTML with each nested element on its own line, indented by
+/// `indent` spaces per level. Empty elements and elements that contain only text are
+/// written on a single line. Text is written as-is, since the parser doesn't decode
+/// character references either.
+pub fn format_html(root: &Node, indent: usize) -> String {
+    let mut output = String::new();
+    format_node(root, indent, 0, &mut output);
+    output
+}
+
+fn format_node(node: &Node, indent: usize, depth: usize, output: &mut String) {
+    let padding = " ".repeat(indent * depth);
+    let elem = match node.node_type {
+        NodeType::Text(ref text) => {
+            output.push_str(&padding);
+            output.push_str(text.trim());
+            output.push('\n');
+            return;
+        }
+        NodeType::Comment(ref text) => {
+            output.push_str(&format!("{}<!--{}-->\n", padding, text));
+            return;
+        }
+        NodeType::Element(ref elem) => elem,
+    };
+
+    output.push_str(&padding);
+    output.push_str(&open_tag(elem));
+    // imgは閉じタグを持たない
+    if elem.tag_name == "img" {
+        output.push('\n');
+        return;
+    }
+
+    let inline = node
+        .children
+        .iter()
+        .all(|child| matches!(child.node_type, NodeType::Text(_)));
+    if inline {
+        for child in &node.children {
+            if let NodeType::Text(ref text) = child.node_type {
+                output.push_str(text);
+            }
+        }
+    } else {
+        output.push('\n');
+        for child in &node.children {
+            format_node(child, indent, depth + 1, output);
+        }
+        output.push_str(&padding);
+    }
+    output.push_str(&format!("</{}>\n", elem.tag_name));
+}
+
+// 出力を安定させるため、属性は名前順に並べる
+fn open_tag(elem: &ElementData) -> String {
+    let mut names: Vec<&String> = elem.attributes.keys().collect();
+    names.sort();
+    let mut tag = format!("<{}", elem.tag_name);
+    for name in names {
+        tag.push_str(&format!(" {}=\"{}\"", name, elem.attributes[name]));
+    }
+    tag.push_str(if elem.tag_name == "img" { "/>" } else { ">" });
+    tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html;
+
+    #[test]
+    fn links_are_collected_in_order() {
+        let root = html::parse(
+            "<div><a href='one.html'>1</a><p><a href='two.html'>2</a></p><a>3</a></div>"
+                .to_string(),
+        );
+        assert_eq!(collect_links(&root), ["one.html", "two.html"]);
+    }
+
+    #[test]
+    fn parsed_trees_compare_structurally() {
+        let a = html::parse("<div id='x' class='y'><p>a</p></div>".to_string());
+        let b = html::parse("<div class='y' id='x'><p>a</p></div>".to_string());
+        assert_eq!(a, b);
+        let c = html::parse("<div class='y' id='x'><p>b</p></div>".to_string());
+        assert_ne!(a, c);
+        let expected = elem(
+            "div".to_string(),
+            HashMap::from([
+                ("id".to_string(), "x".to_string()),
+                ("class".to_string(), "y".to_string()),
+            ]),
+            vec![elem(
+                "p".to_string(),
+                HashMap::new(),
+                vec![text("a".to_string())],
+            )],
+        );
+        assert_eq!(a, expected);
+    }
+
+    #[test]
+    fn style_elements_become_stylesheets() {
+        let root = html::parse(
+            "<html><head><style>p{color:red;}</style></head><body><p>a</p></body></html>"
+                .to_string(),
+        );
+        let stylesheets = extract_stylesheets(&root);
+        assert_eq!(stylesheets.len(), 1);
+        assert_eq!(stylesheets[0].rules.len(), 1);
+        assert_eq!(stylesheets[0].rules[0].declarations[0].name, "color");
+    }
+
+    #[test]
+    fn attr_reads_data_attributes() {
+        // html::parseはまだ属性名のハイフンを読めないので、DOMを直接組み立てる
+        let attrs = AttrMap::from([
+            ("data-user-id".to_string(), "42".to_string()),
+            ("hidden".to_string(), String::new()),
+        ]);
+        let root = elem("div".to_string(), attrs, vec![text("x".to_string())]);
+        let NodeType::Element(ref elem) = root.node_type else {
+            panic!("expected an element");
+        };
+        assert_eq!(elem.attr("data-user-id"), Some("42"));
+        assert_eq!(elem.attr("hidden"), Some(""));
+        assert_eq!(elem.attr("data-missing"), None);
+    }
+
+    #[test]
+    fn format_html_indents_each_level() {
+        let root =
+            html::parse("<div id='a'><p>text</p><ul><li>x</li><li></li></ul></div>".to_string());
+        let expected = "\
+<div id=\"a\">
+   <p>text</p>
+   <ul>
+      <li>x</li>
+      <li></li>
+   </ul>
+</div>
+";
+        assert_eq!(format_html(&root, 3), expected);
+        assert!(format_html(&root, 0).starts_with("<div id=\"a\">\n<p>text</p>\n<ul>\n<li>"));
+    }
+
+    #[test]
+    fn inner_text_breaks_lines_around_blocks() {
+        let blocks = html::parse("<p>a</p><p>b</p>".to_string());
+        assert_eq!(inner_text(&blocks), "a\nb");
+        let inline = html::parse("<span>a</span><span>b</span>".to_string());
+        assert_eq!(inner_text(&inline), "ab");
+        let mixed = html::parse("<div>x  <b>y</b><style>p{}</style><p> z </p></div>".to_string());
+        assert_eq!(inner_text(&mixed), "x y\nz");
+    }
+
+    #[test]
+    fn text_content_concatenates_descendant_text() {
+        let root = html::parse("<p>a<span>b</span>c</p>".to_string());
+        assert_eq!(root.text_content(), "abc");
+        let spaced = html::parse("<p>a  <b>b</b></p>".to_string());
+        assert_eq!(spaced.text_content(), "a  b");
+    }
+}
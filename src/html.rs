@@ -1,28 +1,203 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
 
 use crate::dom;
 
+/// The nesting depth `parse` allows before it stops reading the document.
+pub const DEFAULT_MAX_DEPTH: usize = 1024;
+
+#[derive(Debug)]
+pub enum ParseError {
+    TooDeep(usize),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::TooDeep(max_depth) => {
+                write!(f, "elements nested more than {} levels deep", max_depth)
+            }
+        }
+    }
+}
+
+/// Parse an HTML document. Content nested deeper than `DEFAULT_MAX_DEPTH` is
+/// dropped, along with the rest of the document after it. Use `parse_with_max_depth`
+/// to get a `ParseError::TooDeep` instead.
 pub fn parse(source: String) -> dom::Node {
-    let mut nodes = Parser {
-        pos: 0,
-        input: source,
+    let (root, _) = parse_limited(source, DEFAULT_MAX_DEPTH);
+    root
+}
+
+/// Like `parse`, but borrows the source instead of taking ownership of it. Content
+/// nested too deeply is dropped the same way.
+pub fn parse_str(source: &str) -> dom::Node {
+    Parser::with_input(Cow::Borrowed(source)).parse()
+}
+
+/// Like `parse`, but fails if elements are nested more than `max_depth` levels deep.
+pub fn parse_with_max_depth(source: String, max_depth: usize) -> Result<dom::Node, ParseError> {
+    match parse_limited(source, max_depth) {
+        (root, false) => Ok(root),
+        (_, true) => Err(ParseError::TooDeep(max_depth)),
     }
-    .parse_nodes();
+}
+
+/// Like `parse`, but also reports whether the document is in quirks or standards mode.
+/// Content nested too deeply is dropped, as in `parse`.
+pub fn parse_document(source: String) -> dom::Document {
+    Parser::new(source).parse_document()
+}
 
+// 文書全体を解析し、深さの上限を超えたかどうかと一緒に返す
+fn parse_limited(source: String, max_depth: usize) -> (dom::Node, bool) {
+    let mut parser = Parser::new(source);
+    parser.max_depth = max_depth;
+    let root = parser.parse();
+    (root, parser.too_deep)
+}
+
+fn wrap_document(mut nodes: Vec<dom::Node>) -> dom::Node {
     if nodes.len() == 1 {
-        nodes.swap_remove(0)
+        return nodes.swap_remove(0);
+    }
+
+    // Wrap the content in an implicit `html > body`, keeping a leading `head` (or an
+    // explicit `body`) as a direct child of `html`.
+    let has_body = nodes.iter().any(|node| is_element(node, "body"));
+    let children = if has_body {
+        nodes
     } else {
-        dom::elem("html".to_string(), HashMap::new(), nodes)
+        let body_start = nodes
+            .iter()
+            .position(|node| !is_element(node, "head"))
+            .unwrap_or(nodes.len());
+        let body = dom::elem(
+            "body".to_string(),
+            HashMap::new(),
+            nodes.split_off(body_start),
+        );
+        nodes.push(body);
+        nodes
+    };
+    dom::elem("html".to_string(), HashMap::new(), children)
+}
+
+fn is_element(node: &dom::Node, tag_name: &str) -> bool {
+    match node.node_type {
+        dom::NodeType::Element(ref elem) => elem.tag_name == tag_name,
+        dom::NodeType::Text(_) | dom::NodeType::Comment(_) => false,
+    }
+}
+
+/// Decode raw HTML bytes into a string, using the encoding from `<meta charset>` if
+/// one appears near the start of the document. Defaults to UTF-8, and falls back to
+/// Latin-1 when the bytes aren't valid UTF-8.
+pub fn decode_html(bytes: &[u8]) -> String {
+    match sniff_charset(bytes).as_deref() {
+        Some("iso-8859-1") | Some("latin1") | Some("windows-1252") | Some("us-ascii") => {
+            decode_latin1(bytes)
+        }
+        _ => match std::str::from_utf8(bytes) {
+            Ok(s) => s.to_string(),
+            Err(_) => decode_latin1(bytes),
+        },
+    }
+}
+
+fn decode_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+// The charset declaration must appear within the first 1024 bytes.
+fn sniff_charset(bytes: &[u8]) -> Option<String> {
+    let head = &bytes[..bytes.len().min(1024)];
+    let head = decode_latin1(head).to_ascii_lowercase();
+    let mut rest = &head[..];
+    while let Some(start) = rest.find("<meta") {
+        rest = &rest[start + "<meta".len()..];
+        let tag = &rest[..rest.find('>').unwrap_or(rest.len())];
+        if let Some(i) = tag.find("charset=") {
+            let value = tag[i + "charset=".len()..].trim_start_matches(['"', '\'']);
+            let end = value
+                .find(|c: char| c == '"' || c == '\'' || c == ';' || c == '/' || c.is_whitespace())
+                .unwrap_or(value.len());
+            return Some(value[..end].to_string());
+        }
     }
+    None
 }
 
-struct Parser {
+/// An HTML parser. `reset` lets one parser be reused for several documents.
+pub struct Parser<'a> {
     pos: usize,
-    input: String,
+    input: Cow<'a, str>,
+    // 現在の要素の入れ子の深さ
+    depth: usize,
+    // 閉じタグを待っている要素のタグ名。外側の要素から順に並ぶ
+    open_elements: Vec<String>,
+    max_depth: usize,
+    too_deep: bool,
+    keep_comments: bool,
+    // 文書の先頭に`<!DOCTYPE html>`があったかどうか
+    html_doctype: bool,
 }
 
-impl Parser {
+impl<'a> Parser<'a> {
+    pub fn new(input: String) -> Parser<'a> {
+        Parser::with_input(Cow::Owned(input))
+    }
+
+    fn with_input(input: Cow<'a, str>) -> Parser<'a> {
+        Parser {
+            pos: 0,
+            input: input,
+            depth: 0,
+            open_elements: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            too_deep: false,
+            keep_comments: false,
+            html_doctype: false,
+        }
+    }
+
+    /// Replace the input with a new document and start over from its beginning.
+    pub fn reset(&mut self, input: String) {
+        self.input = Cow::Owned(input);
+        self.pos = 0;
+        self.depth = 0;
+        self.open_elements.clear();
+        self.too_deep = false;
+        self.html_doctype = false;
+    }
+
+    /// Keep comments in the tree as `NodeType::Comment` nodes instead of dropping them.
+    pub fn set_keep_comments(&mut self, keep_comments: bool) {
+        self.keep_comments = keep_comments;
+    }
+
+    /// Parse the input into a document, like `html::parse`. Content nested deeper than
+    /// `DEFAULT_MAX_DEPTH` is dropped along with the rest of the input.
+    pub fn parse(&mut self) -> dom::Node {
+        let nodes = self.parse_nodes();
+        wrap_document(nodes)
+    }
+
+    /// Parse the input into a document, like `html::parse_document`. The document is
+    /// in standards mode if it starts with `<!DOCTYPE html>`, and in quirks mode otherwise.
+    pub fn parse_document(&mut self) -> dom::Document {
+        let root = self.parse();
+        let mode = if self.html_doctype {
+            dom::Mode::Standards
+        } else {
+            dom::Mode::Quirks
+        };
+        dom::Document::new(root, mode)
+    }
+
     fn next_char(&self) -> char {
+        debug_assert!(self.input.is_char_boundary(self.pos));
         self.input[self.pos..].chars().next().unwrap()
     }
 
@@ -33,11 +208,11 @@ impl Parser {
     fn eof(&self) -> bool {
         self.pos >= self.input.len()
     }
+    // Advance by the character's encoded length so `pos` always stays on a char
+    // boundary, even for a multi-byte character at the end of the input.
     fn consume_char(&mut self) -> char {
-        let mut iter = self.input[self.pos..].char_indices();
-        let (_, cur_char) = iter.next().unwrap();
-        let (next_pos, _) = iter.next().unwrap_or((1, ' '));
-        self.pos += next_pos;
+        let cur_char = self.next_char();
+        self.pos += cur_char.len_utf8();
         return cur_char;
     }
 
@@ -53,21 +228,54 @@ impl Parser {
         return result;
     }
 
-    fn consume_comment(&mut self) {
-        assert!(self.consume_char() == '<');
-        assert!(self.consume_char() == '!');
-        assert!(self.consume_char() == '-');
-        assert!(self.consume_char() == '-');
-
-        while !self.eof() {
-            if self.starts_with("-->") {
-                assert!(self.consume_char() == '-');
-                assert!(self.consume_char() == '-');
-                assert!(self.consume_char() == '>');
-                break;
-            } else {
-                self.consume_char();
-            }
+    // Consume characters up to (but not including) `terminator`, or to the end of the input.
+    fn consume_until_str(&mut self, terminator: &str) -> String {
+        let end = match self.input[self.pos..].find(terminator) {
+            Some(offset) => self.pos + offset,
+            None => self.input.len(),
+        };
+        let result = self.input[self.pos..end].to_string();
+        self.pos = end;
+        result
+    }
+
+    // Comments without a closing `-->` extend to the end of the input.
+    fn consume_comment(&mut self) -> String {
+        assert!(self.starts_with("<!--"));
+        self.pos += "<!--".len();
+        let text = self.consume_until_str("-->");
+        if !self.eof() {
+            self.pos += "-->".len();
+        }
+        text
+    }
+
+    // The doctype's name is returned, and the rest of it is ignored.
+    fn consume_doctype(&mut self) -> String {
+        self.pos += "<!".len();
+        self.consume_while(|c| !c.is_whitespace() && c != '>');
+        self.consume_whitespace();
+        let name = self.consume_while(|c| !c.is_whitespace() && c != '>');
+        self.consume_until_str(">");
+        if !self.eof() {
+            self.pos += ">".len();
+        }
+        name
+    }
+
+    fn starts_with_doctype(&self) -> bool {
+        self.input[self.pos..]
+            .get(.."<!doctype".len())
+            .is_some_and(|s| s.eq_ignore_ascii_case("<!doctype"))
+    }
+
+    // Processing instructions and XML declarations (`<?xml ... ?>`) are skipped.
+    fn consume_processing_instruction(&mut self) {
+        assert!(self.starts_with("<?"));
+        self.pos += "<?".len();
+        self.consume_until_str("?>");
+        if !self.eof() {
+            self.pos += "?>".len();
         }
     }
 
@@ -104,20 +312,50 @@ impl Parser {
             return dom::elem(tag_name, attrs, vec![]);
         } else {
             assert!(self.consume_char() == '>');
-            let children = self.parse_nodes();
+            let children = if self.is_raw_text(&tag_name) {
+                self.parse_raw_text(&tag_name)
+            } else {
+                self.depth += 1;
+                self.open_elements.push(tag_name.clone());
+                let children = self.parse_nodes();
+                self.open_elements.pop();
+                self.depth -= 1;
+                children
+            };
 
-            assert!(self.consume_char() == '<');
-            assert!(self.consume_char() == '/');
-            assert!(self.parse_tag_name() == tag_name);
-            assert!(self.consume_char() == '>');
+            // Elements left open at the end of the input are closed implicitly, and so
+            // are elements whose ancestor's closing tag comes first, as in `<b><i>x</b>`.
+            if self.eof() || self.peek_closing_tag() != tag_name {
+                return dom::elem(tag_name, attrs, children);
+            }
+            self.consume_closing_tag();
 
             return dom::elem(tag_name, attrs, children);
         }
     }
 
+    // 現在の位置にある閉じタグのタグ名を、読み進めずに返す
+    fn peek_closing_tag(&self) -> String {
+        self.input[self.pos + "</".len()..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect()
+    }
+
+    fn consume_closing_tag(&mut self) {
+        assert!(self.consume_char() == '<');
+        assert!(self.consume_char() == '/');
+        self.parse_tag_name();
+        assert!(self.consume_char() == '>');
+    }
+
+    // 値のない属性(`<div hidden>`)の値は空文字列にする
     fn parse_attr(&mut self) -> (String, String) {
-        let name = self.parse_tag_name();
-        assert!(self.consume_char() == '=');
+        let name = self.consume_while(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if self.next_char() != '=' {
+            return (name, String::new());
+        }
+        self.consume_char();
         let value = self.parse_attr_value();
         return (name, value);
     }
@@ -146,21 +384,233 @@ impl Parser {
 
     fn parse_nodes(&mut self) -> Vec<dom::Node> {
         let mut nodes = Vec::new();
+        // 深すぎる入れ子は再帰でスタックを使い果たすので、残りの入力を読み捨てる
+        if self.depth > self.max_depth {
+            self.too_deep = true;
+            self.pos = self.input.len();
+            return nodes;
+        }
         loop {
             self.consume_whitespace();
             if self.starts_with("<!--") {
-                self.consume_comment();
+                let text = self.consume_comment();
+                if self.keep_comments {
+                    nodes.push(dom::comment(text));
+                }
                 continue;
             }
-            if self.eof() || self.starts_with("</") {
+            if self.starts_with("<?") {
+                self.consume_processing_instruction();
+                continue;
+            }
+            if self.starts_with_doctype() {
+                // 文書の先頭以外にあるdoctypeは読み捨てる
+                let at_start = self.depth == 0 && nodes.is_empty();
+                let name = self.consume_doctype();
+                if at_start && name.eq_ignore_ascii_case("html") {
+                    self.html_doctype = true;
+                }
+                continue;
+            }
+            if self.eof() {
                 break;
             }
+            if self.starts_with("</") {
+                // 開いている要素の閉じタグでなければ読み捨てる
+                if self.open_elements.contains(&self.peek_closing_tag()) {
+                    break;
+                }
+                self.consume_closing_tag();
+                continue;
+            }
             nodes.push(self.parse_node())
         }
         return nodes;
     }
 
+    // The contents of raw text elements run up to the closing tag without being parsed.
+    fn parse_raw_text(&mut self, tag_name: &str) -> Vec<dom::Node> {
+        let text = self.consume_until_str(&format!("</{}", tag_name));
+        if text.is_empty() {
+            vec![]
+        } else {
+            vec![dom::text(text)]
+        }
+    }
+
+    fn is_raw_text(&self, tag_name: &str) -> bool {
+        matches!(tag_name, "style" | "script")
+    }
+
     fn is_self_closing(&self, tag_name: &str) -> bool {
         matches!(tag_name, "img")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unterminated_comment_runs_to_the_end() {
+        let root = parse("<div><!-- unterminated".to_string());
+        match root.node_type {
+            dom::NodeType::Element(ref elem) => assert_eq!(elem.tag_name, "div"),
+            _ => panic!("expected an element"),
+        }
+        assert!(root.children.is_empty());
+    }
+
+    #[test]
+    fn xml_declaration_is_skipped() {
+        let root = parse("<?xml version=\"1.0\"?><a></a>".to_string());
+        match root.node_type {
+            dom::NodeType::Element(ref elem) => assert_eq!(elem.tag_name, "a"),
+            _ => panic!("expected an element"),
+        }
+        assert!(root.children.is_empty());
+    }
+
+    #[test]
+    fn meta_charset_selects_latin1() {
+        let mut bytes = b"<meta charset=\"iso-8859-1\"><p>caf".to_vec();
+        bytes.extend([0xe9, b' ', 0xa9]);
+        bytes.extend(b"</p>");
+        assert_eq!(
+            decode_html(&bytes),
+            "<meta charset=\"iso-8859-1\"><p>caf\u{e9} \u{a9}</p>"
+        );
+        // 指定がなければUTF-8として読む
+        assert_eq!(decode_html("caf\u{e9}".as_bytes()), "caf\u{e9}");
+    }
+
+    #[test]
+    fn several_roots_are_wrapped_in_html_and_body() {
+        let p = |text: &str| {
+            dom::elem(
+                "p".to_string(),
+                HashMap::new(),
+                vec![dom::text(text.to_string())],
+            )
+        };
+        let body = dom::elem("body".to_string(), HashMap::new(), vec![p("a"), p("b")]);
+        let expected = dom::elem("html".to_string(), HashMap::new(), vec![body]);
+        assert_eq!(parse("<p>a</p><p>b</p>".to_string()), expected);
+    }
+
+    #[test]
+    fn consume_until_stops_before_the_terminator() {
+        let mut parser = Parser::new("a -- b -> c --> d".to_string());
+        assert_eq!(parser.consume_until_str("-->"), "a -- b -> c ");
+        assert!(parser.starts_with("-->"));
+        let mut parser = Parser::new("no end".to_string());
+        assert_eq!(parser.consume_until_str("-->"), "no end");
+        assert!(parser.eof());
+
+        let root = parse("<p>a<!-- x -- y -->b</p>".to_string());
+        let texts: Vec<&str> = root
+            .children
+            .iter()
+            .map(|child| match child.node_type {
+                dom::NodeType::Text(ref text) => text.as_str(),
+                _ => panic!("expected text"),
+            })
+            .collect();
+        assert_eq!(texts, ["a", "b"]);
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let source = "<div>".repeat(10_000) + &"</div>".repeat(10_000);
+        let result = parse_with_max_depth(source.clone(), DEFAULT_MAX_DEPTH);
+        assert!(matches!(
+            result,
+            Err(ParseError::TooDeep(DEFAULT_MAX_DEPTH))
+        ));
+        assert!(parse_with_max_depth("<div><p>a</p></div>".to_string(), 2).is_ok());
+        // 他の関数は深すぎる部分を捨てて続ける
+        assert_eq!(parse_str(&source).children.len(), 1);
+        assert_eq!(parse_document(source.clone()).root.children.len(), 1);
+        assert_eq!(parse(source).children.len(), 1);
+    }
+
+    #[test]
+    fn reset_parser_reads_a_second_document() {
+        let mut parser = Parser::new("<div><p>one".to_string());
+        let first = parser.parse();
+        assert_eq!(first, parse("<div><p>one</p></div>".to_string()));
+
+        parser.reset("<span>two</span>".to_string());
+        let second = parser.parse();
+        assert_eq!(second, parse("<span>two</span>".to_string()));
+    }
+
+    #[test]
+    fn parse_str_matches_parse() {
+        let source = "<html><body><div class='a'>x<!-- c --><p>y</p></div></body></html>";
+        assert_eq!(parse_str(source), parse(source.to_string()));
+    }
+
+    fn text_of(node: &dom::Node) -> String {
+        match node.node_type {
+            dom::NodeType::Text(ref text) => text.clone(),
+            _ => node.children.iter().map(text_of).collect(),
+        }
+    }
+
+    #[test]
+    fn emoji_between_tags() {
+        let root = parse("<p>🦀é</p><p title='✨'>日本語</p>🎉".to_string());
+        assert_eq!(text_of(&root), "🦀é日本語🎉");
+        // 終わりが複数バイトの文字でも境界で止まる
+        assert_eq!(text_of(&parse("<p>🦀".to_string())), "🦀");
+    }
+
+    #[test]
+    fn mismatched_closing_tags_close_the_nearest_match() {
+        let root = parse("<div><b><i>x</b></i>y</div>".to_string());
+        let italic = dom::elem(
+            "i".to_string(),
+            HashMap::new(),
+            vec![dom::text("x".to_string())],
+        );
+        let bold = dom::elem("b".to_string(), HashMap::new(), vec![italic]);
+        let expected = dom::elem(
+            "div".to_string(),
+            HashMap::new(),
+            vec![bold, dom::text("y".to_string())],
+        );
+        assert_eq!(root, expected);
+    }
+
+    #[test]
+    fn comments_are_kept_on_request() {
+        let mut parser = Parser::new("<div><!-- hi --></div>".to_string());
+        parser.set_keep_comments(true);
+        let root = parser.parse();
+        let comment = dom::Node {
+            children: vec![],
+            node_type: dom::NodeType::Comment(" hi ".to_string()),
+        };
+        assert_eq!(
+            root,
+            dom::elem("div".to_string(), HashMap::new(), vec![comment])
+        );
+
+        let dropped = parse("<div><!-- hi --></div>".to_string());
+        assert_eq!(
+            dropped,
+            dom::elem("div".to_string(), HashMap::new(), vec![])
+        );
+    }
+
+    #[test]
+    fn doctype_selects_the_document_mode() {
+        let quirks = parse_document("<html><body>a</body></html>".to_string());
+        assert_eq!(quirks.mode(), dom::Mode::Quirks);
+        let standards = parse_document("<!DOCTYPE html><html><body>a</body></html>".to_string());
+        assert_eq!(standards.mode(), dom::Mode::Standards);
+        let lowercase = parse_document("<!doctype html><p>a</p>".to_string());
+        assert_eq!(lowercase.mode(), dom::Mode::Standards);
+    }
+}
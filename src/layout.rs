@@ -1,14 +1,21 @@
 pub use self::BoxType::{AnonymousBlock, BlockNode, InlineNode};
+use crate::text::{self, CachedMeasurer, MonospaceMeasurer, TextMeasurer};
 use crate::{
     css,
+    dom::Mode,
     style::{
-        Display::{Block, Inline, None},
+        is_whitespace_next_to_block,
+        Display::{self, Block, Flex, Inline, InlineBlock, Table, TableCell, TableRow},
         StyledNode,
     },
 };
-use css::Unit::Px;
-use css::Value::{Keyword, Length};
+use css::Unit::{Percent, Px};
+use css::Value::{self, Keyword, Length};
+use std::borrow::Cow;
 use std::default::Default;
+use std::mem;
+use std::ops::Range;
+use std::vec;
 
 #[derive(Default, Debug, Clone)]
 pub struct Dimensions {
@@ -19,8 +26,12 @@ pub struct Dimensions {
 }
 
 impl Dimensions {
+    pub fn content_box(self) -> Rect {
+        self.content
+    }
+
     // paddingの大きさ分足す
-    fn padding_box(self) -> Rect {
+    pub fn padding_box(self) -> Rect {
         self.content.expanded_by(self.padding)
     }
 
@@ -30,11 +41,30 @@ impl Dimensions {
     }
 
     // paddingの大きさ + borderの太さ + marginの大きさ分足す
-    fn margin_box(self) -> Rect {
+    pub fn margin_box(self) -> Rect {
         self.border_box().expanded_by(self.margin)
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Viewport {
+    pub dimensions: Dimensions,
+    // ルート要素のfont-size。remの解決やfont-sizeが指定されていない場合に使う
+    pub root_font_size: f32,
+    // 文書のモード。quirksモードでは%の高さの扱いが変わる
+    pub mode: Mode,
+}
+
+impl Default for Viewport {
+    fn default() -> Viewport {
+        Viewport {
+            dimensions: Default::default(),
+            root_font_size: 16.0,
+            mode: Mode::Standards,
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct Rect {
     pub x: f32,
@@ -44,6 +74,30 @@ pub struct Rect {
 }
 
 impl Rect {
+    /// Return the overlapping area of two rectangles, or `None` if they don't overlap.
+    /// Rectangles that only touch along an edge don't overlap.
+    pub fn intersect(&self, other: Rect) -> Option<Rect> {
+        let x0 = self.x.max(other.x);
+        let y0 = self.y.max(other.y);
+        let x1 = (self.x + self.width).min(other.x + other.width);
+        let y1 = (self.y + self.height).min(other.y + other.height);
+        if x1 <= x0 || y1 <= y0 {
+            return None;
+        }
+        Some(Rect {
+            x: x0,
+            y: y0,
+            width: x1 - x0,
+            height: y1 - y0,
+        })
+    }
+
+    /// Return whether the point lies inside the rectangle. The left and top edges are
+    /// inside; the right and bottom edges are not.
+    pub fn contains_point(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+
     fn expanded_by(self, edge: EdgeSizes) -> Rect {
         Rect {
             x: self.x - edge.left,
@@ -71,6 +125,14 @@ pub struct LayoutBox<'a> {
     pub dimensions: Dimensions,
     pub box_type: BoxType<'a>,
     pub children: Vec<LayoutBox<'a>>,
+    pub font_size: f32,
+    // 再レイアウトが必要かどうか
+    dirty: bool,
+    // 行をまたいで分割されたテキストの場合、このボックスが受け持つ範囲
+    text_range: Option<Range<usize>>,
+    // %で指定された高さの基準になる包含ブロックの高さ。決まっていなければNone
+    containing_height: Option<f32>,
+    mode: Mode,
 }
 
 #[derive(Debug)]
@@ -81,11 +143,187 @@ pub enum BoxType<'a> {
 }
 
 impl<'a> LayoutBox<'a> {
-    fn new(box_type: BoxType) -> LayoutBox {
+    fn new(box_type: BoxType, font_size: f32) -> LayoutBox {
         LayoutBox {
             box_type: box_type,
             dimensions: Default::default(),
             children: Vec::new(),
+            font_size: font_size,
+            dirty: false,
+            text_range: None,
+            containing_height: None,
+            mode: Mode::Standards,
+        }
+    }
+
+    /// Return the text laid out in this box, if it is a text box. A text node that was
+    /// broken across lines is split into several boxes, each holding part of the text.
+    pub fn text(&self) -> Option<&'a str> {
+        let text = match self.box_type {
+            BlockNode(style) | InlineNode(style) => style.text()?,
+            AnonymousBlock => return None,
+        };
+        match self.text_range {
+            Some(ref range) => Some(&text[range.clone()]),
+            None => Some(text),
+        }
+    }
+
+    /// Mark this box so that the next `relayout_tree` lays out its whole subtree again.
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Return the content box in viewport coordinates. Layout positions every box
+    /// relative to the viewport, so no ancestor offsets need to be added.
+    pub fn absolute_rect(&self) -> Rect {
+        self.dimensions.content
+    }
+
+    /// Like `absolute_rect`, but for the border box.
+    pub fn absolute_border_rect(&self) -> Rect {
+        self.dimensions.border_box()
+    }
+
+    /// Return the distance from the top of the content box to the text baseline. A box
+    /// with children uses the baseline of its last line, and an image its bottom edge.
+    pub fn baseline(&self) -> f32 {
+        if let Some(last) = self.children.last() {
+            return last.dimensions.content.y - self.dimensions.content.y + last.baseline();
+        }
+        let is_image = match self.box_type {
+            BlockNode(style) | InlineNode(style) => style.tag_name() == Some("img"),
+            AnonymousBlock => false,
+        };
+        if is_image {
+            return self.dimensions.content.height;
+        }
+        // フォントの情報がないので、アセントをfont-sizeの8割と仮定する
+        self.font_size * 0.8
+    }
+
+    /// Count the boxes in this subtree, including this one, whose type satisfies `pred`.
+    pub fn count_boxes<F>(&self, pred: F) -> usize
+    where
+        F: Fn(&BoxType) -> bool,
+    {
+        self.count_boxes_dyn(&pred)
+    }
+
+    fn count_boxes_dyn(&self, pred: &dyn Fn(&BoxType) -> bool) -> usize {
+        let count = if pred(&self.box_type) { 1 } else { 0 };
+        count
+            + self
+                .children
+                .iter()
+                .map(|child| child.count_boxes_dyn(pred))
+                .sum::<usize>()
+    }
+
+    /// Return the first box in this subtree, in tree order, whose type satisfies `pred`.
+    pub fn find_first<F>(&self, pred: F) -> Option<&LayoutBox<'a>>
+    where
+        F: Fn(&BoxType) -> bool,
+    {
+        self.find_first_dyn(&pred)
+    }
+
+    fn find_first_dyn(&self, pred: &dyn Fn(&BoxType) -> bool) -> Option<&LayoutBox<'a>> {
+        if pred(&self.box_type) {
+            return Some(self);
+        }
+        self.children
+            .iter()
+            .find_map(|child| child.find_first_dyn(pred))
+    }
+
+    /// Return the scrollable width and height: the content box extended to cover
+    /// every descendant box.
+    pub fn scroll_size(&self) -> (f32, f32) {
+        let content = self.dimensions.content;
+        let (right, bottom) = self.descendants_extent();
+        (
+            content.width.max(right - content.x),
+            content.height.max(bottom - content.y),
+        )
+    }
+
+    // 子孫要素の右端と下端の最大値。
+    // 幅がはみ出したブロックはmargin-rightが負の値になるので、右端はborder boxで測る
+    fn descendants_extent(&self) -> (f32, f32) {
+        let mut right = f32::MIN;
+        let mut bottom = f32::MIN;
+        for child in &self.children {
+            let border_box = child.dimensions.border_box();
+            let margin_box = child.dimensions.margin_box();
+            let (child_right, child_bottom) = child.descendants_extent();
+            right = right.max(border_box.x + border_box.width).max(child_right);
+            bottom = bottom
+                .max(margin_box.y + margin_box.height)
+                .max(child_bottom);
+        }
+        (right, bottom)
+    }
+
+    fn needs_layout(&self) -> bool {
+        self.dirty || self.children.iter().any(|child| child.needs_layout())
+    }
+
+    fn clear_dirty(&mut self) {
+        self.dirty = false;
+        for child in &mut self.children {
+            child.clear_dirty();
+        }
+    }
+
+    // 汚れたボックスの部分木はレイアウトし直し、その祖先は高さと位置だけを計算し直す。
+    // 汚れていない部分木は大きさが変わらないので、移動するだけにする。
+    fn relayout(
+        &mut self,
+        containing_block: Dimensions,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+        observer: &mut dyn FnMut(&str, &Dimensions),
+    ) {
+        if self.dirty {
+            self.layout(containing_block, root_font_size, measurer, observer);
+            self.clear_dirty();
+            return;
+        }
+
+        if !self.needs_layout() {
+            let margin_box = self.dimensions.margin_box();
+            self.translate(
+                containing_block.content.x - margin_box.x,
+                containing_block.content.y + containing_block.content.height - margin_box.y,
+            );
+            return;
+        }
+
+        match self.box_type {
+            BlockNode(_) if self.is_block_flow() => {
+                // 幅は子要素に依存しないので計算し直さない
+                let ctx = self.style_context(root_font_size, containing_block.content.width);
+                self.calculate_block_position(containing_block, &ctx);
+                self.dimensions.content.height = 0.0;
+                observer("position", &self.dimensions);
+                let d = &mut self.dimensions;
+                for child in &mut self.children {
+                    child.relayout(*d, root_font_size, measurer, observer);
+                    d.content.height += child.dimensions.margin_box().height;
+                }
+                self.calculate_block_height(&ctx);
+                observer("height", &self.dimensions);
+            }
+            // 行の分割やフレックスアイテムの配置は子要素全体に依存するので全体をレイアウトし直す
+            _ => {
+                self.layout(containing_block, root_font_size, measurer, observer);
+                self.clear_dirty();
+            }
         }
     }
 
@@ -96,6 +334,24 @@ impl<'a> LayoutBox<'a> {
         }
     }
 
+    // 隣り合った匿名ブロックボックスは一つにまとめる
+    fn merge_anonymous_blocks(&mut self) {
+        let children = mem::take(&mut self.children);
+        for mut child in children {
+            match (self.children.last_mut(), &child.box_type) {
+                (
+                    Some(LayoutBox {
+                        box_type: AnonymousBlock,
+                        children: previous,
+                        ..
+                    }),
+                    AnonymousBlock,
+                ) => previous.append(&mut child.children),
+                _ => self.children.push(child),
+            }
+        }
+    }
+
     fn get_inline_container(&mut self) -> &mut LayoutBox<'a> {
         match self.box_type {
             InlineNode(_) | AnonymousBlock => self,
@@ -106,40 +362,409 @@ impl<'a> LayoutBox<'a> {
                         box_type: AnonymousBlock,
                         ..
                     }) => {}
-                    _ => self.children.push(LayoutBox::new(AnonymousBlock)),
+                    _ => self
+                        .children
+                        .push(LayoutBox::new(AnonymousBlock, self.font_size)),
                 }
                 self.children.last_mut().unwrap()
             }
         }
     }
 
-    fn layout(&mut self, containing_block: Dimensions) {
+    fn layout(
+        &mut self,
+        containing_block: Dimensions,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+        observer: &mut dyn FnMut(&str, &Dimensions),
+    ) {
         match self.box_type {
-            BlockNode(_) => self.layout_block(containing_block),
+            BlockNode(_) => self.layout_block(containing_block, root_font_size, measurer, observer),
+            // インラインボックスは親の匿名ブロックボックスが配置する
             InlineNode(_) => {}
-            AnonymousBlock => {}
+            AnonymousBlock => {
+                self.layout_anonymous_block(containing_block, root_font_size, measurer)
+            }
+        }
+    }
+
+    fn layout_anonymous_block(
+        &mut self,
+        containing_block: Dimensions,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+    ) {
+        let d = &mut self.dimensions;
+        d.content.x = containing_block.content.x;
+        d.content.y = containing_block.content.y + containing_block.content.height;
+        d.content.width = containing_block.content.width;
+
+        self.layout_inline_children(root_font_size, measurer);
+    }
+
+    // インラインボックスを左から順に並べ、幅が足りなくなったら次の行に送る
+    fn layout_inline_children(&mut self, root_font_size: f32, measurer: &dyn TextMeasurer) {
+        let content = self.dimensions.content;
+        let mut cursor_x = 0.0;
+        let mut line_top = 0.0;
+        let mut line_height: f32 = 0.0;
+
+        let mut line_start = 0;
+        let mut i = 0;
+        while i < self.children.len() {
+            let child = &mut self.children[i];
+            child.layout_inline(root_font_size, measurer);
+            let mut margin_box = child.dimensions.margin_box();
+            // white-space: nowrapの場合は折り返さず、行からはみ出させる
+            let wrap = child.can_wrap();
+
+            if wrap && cursor_x > 0.0 && cursor_x + margin_box.width > content.width {
+                self.align_line(line_start..i, line_height);
+                line_start = i;
+                line_top += line_height;
+                cursor_x = 0.0;
+                line_height = 0.0;
+            }
+
+            let child = &mut self.children[i];
+            // 一行に収まらないテキストは、残りを次のボックスに分ける
+            let rest = if wrap && margin_box.width > content.width {
+                child.break_text(content.width, root_font_size, measurer)
+            } else {
+                None
+            };
+            if rest.is_some() {
+                margin_box = child.dimensions.margin_box();
+            }
+
+            child.translate(
+                content.x + cursor_x - margin_box.x,
+                content.y + line_top - margin_box.y,
+            );
+            cursor_x += margin_box.width;
+            line_height = line_height
+                .max(margin_box.height)
+                .max(child.line_height(root_font_size));
+
+            if let Some(rest) = rest {
+                self.children.insert(i + 1, rest);
+            }
+            i += 1;
+        }
+        self.align_line(line_start..self.children.len(), line_height);
+
+        self.dimensions.content.height = line_top + line_height;
+    }
+
+    // 行の上端に揃えて置いたボックスを、vertical-alignに従って行の中で下にずらす
+    fn align_line(&mut self, line: Range<usize>, line_height: f32) {
+        for child in &mut self.children[line] {
+            let extra = line_height - child.dimensions.margin_box().height;
+            let offset = extra * child.vertical_align_ratio();
+            if offset > 0.0 {
+                child.translate(0.0, offset);
+            }
+        }
+    }
+
+    // vertical-alignのtopは0、middleは0.5、bottomは1。それ以外は上端に揃える
+    fn vertical_align_ratio(&self) -> f32 {
+        match self.box_type {
+            BlockNode(style) | InlineNode(style) => match style.value("vertical-align") {
+                Some(Keyword(value)) if value == "middle" => 0.5,
+                Some(Keyword(value)) if value == "bottom" => 1.0,
+                _ => 0.0,
+            },
+            AnonymousBlock => 0.0,
+        }
+    }
+
+    // インラインボックスの大きさを原点を基準に計算する。位置は親が決める
+    fn layout_inline(&mut self, root_font_size: f32, measurer: &dyn TextMeasurer) {
+        let font_size = self.font_size;
+
+        if let Some(text) = self.text() {
+            let width = self.measure_text(text, root_font_size, measurer);
+            let d = &mut self.dimensions;
+            d.content.width = width;
+            d.content.height = font_size;
+            return;
+        }
+
+        self.calculate_inline_edges(root_font_size);
+
+        if let Some((width, height)) = self.replaced_size(root_font_size) {
+            self.dimensions.content.width = width;
+            self.dimensions.content.height = height;
+            return;
+        }
+
+        // 子要素を一行に並べた大きさを自身の大きさにする
+        let mut cursor_x = 0.0;
+        let mut height: f32 = 0.0;
+        for child in &mut self.children {
+            child.layout_inline(root_font_size, measurer);
+            let margin_box = child.dimensions.margin_box();
+            child.translate(cursor_x - margin_box.x, -margin_box.y);
+            cursor_x += margin_box.width;
+            height = height.max(margin_box.height);
         }
+        // inline-blockはwidth/heightが指定されていればその大きさになる
+        let (width, height) = match self.inline_block_size(root_font_size) {
+            Some((width, explicit_height)) => {
+                (width.unwrap_or(cursor_x), explicit_height.unwrap_or(height))
+            }
+            None => (cursor_x, height),
+        };
+        self.dimensions.content.width = width;
+        self.dimensions.content.height = height;
+    }
+
+    // inline-blockのボックスなら、指定されたwidthとheightを返す。autoはNone
+    fn inline_block_size(&self, root_font_size: f32) -> Option<(Option<f32>, Option<f32>)> {
+        let style = self.get_style_node();
+        if !matches!(style.display(), InlineBlock) {
+            return None;
+        }
+        let ctx = self.style_context(root_font_size, 0.0);
+        let size = |name: &str| match style.value(name) {
+            Some(value @ (Length(..) | Value::Calc(_))) => Some(resolve_length(value, &ctx)),
+            _ => None,
+        };
+        Some((size("width"), size("height")))
+    }
+
+    // line-heightは行の高さになる。normalの場合はfont-sizeと同じにする
+    fn line_height(&self, root_font_size: f32) -> f32 {
+        let font_size = self.font_size;
+        match self.box_type {
+            BlockNode(style) | InlineNode(style) => match style.value("line-height") {
+                Some(&Value::Number(n)) => n * font_size,
+                // %はfont-sizeを基準にする
+                Some(value @ (Length(..) | Value::Calc(_))) => {
+                    resolve_length(value, &self.style_context(root_font_size, font_size))
+                }
+                _ => font_size,
+            },
+            AnonymousBlock => font_size,
+        }
+    }
+
+    fn measure_text(&self, text: &str, root_font_size: f32, measurer: &dyn TextMeasurer) -> f32 {
+        let style = self.get_style_node();
+        let ctx = self.style_context(root_font_size, 0.0);
+        let letter_spacing = style
+            .value("letter-spacing")
+            .map_or(0.0, |v| resolve_length(v, &ctx));
+        text::text_width(measurer, text, self.font_size, letter_spacing)
+    }
+
+    // テキストを幅に収まる位置で分割し、残りのテキストのボックスを返す。空白の直後で分け、
+    // 行末の空白ははみ出してもよい。収まる空白がなければ、overflow-wrap: break-word
+    // (またはword-break: break-all)なら文字の境界で、そうでなければ最初の空白の直後で分ける
+    fn break_text(
+        &mut self,
+        max_width: f32,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+    ) -> Option<LayoutBox<'a>> {
+        let text = self.text()?;
+
+        let mut fit_end = None;
+        let mut space_end = None;
+        for (i, c) in text.char_indices() {
+            let end = i + c.len_utf8();
+            if c.is_whitespace() {
+                space_end = Some(end);
+                continue;
+            }
+            // 少なくとも一文字はこの行に置く
+            if fit_end.is_some()
+                && self.measure_text(&text[..end], root_font_size, measurer) > max_width
+            {
+                break;
+            }
+            fit_end = Some(end);
+        }
+        let split = match space_end {
+            Some(end) => end,
+            None if self.can_break_word() => fit_end?,
+            None => text
+                .char_indices()
+                .find(|&(_, c)| c.is_whitespace())
+                .map(|(i, c)| i + c.len_utf8())?,
+        };
+        if split >= text.len() {
+            return None;
+        }
+
+        let start = self.text_range.as_ref().map_or(0, |range| range.start);
+        let mut rest = LayoutBox::new(InlineNode(self.get_style_node()), self.font_size);
+        rest.text_range = Some(start + split..start + text.len());
+        self.text_range = Some(start..start + split);
+        self.layout_inline(root_font_size, measurer);
+        Some(rest)
+    }
+
+    fn can_wrap(&self) -> bool {
+        match self.box_type {
+            BlockNode(style) | InlineNode(style) => !matches!(
+                style.value("white-space"),
+                Some(Keyword(value)) if value == "nowrap"
+            ),
+            AnonymousBlock => true,
+        }
+    }
+
+    fn can_break_word(&self) -> bool {
+        let style = self.get_style_node();
+        let overflow_wrap = matches!(
+            style.value("overflow-wrap"),
+            Some(Keyword(value)) if value == "break-word" || value == "anywhere"
+        );
+        let word_break = matches!(
+            style.value("word-break"),
+            Some(Keyword(value)) if value == "break-all"
+        );
+        overflow_wrap || word_break
+    }
+
+    fn calculate_inline_edges(&mut self, root_font_size: f32) {
+        let style = self.get_style_node();
+        // インラインボックスの包含ブロックの幅はここでは分からないので、%は0になる
+        let ctx = self.style_context(root_font_size, 0.0);
+        let px = |v: Value| resolve_length(&v, &ctx);
+        let zero = Length(0.0, Px);
+        let d = &mut self.dimensions;
+
+        d.margin.left = px(style.lookup("margin-left", "margin", &zero));
+        d.margin.right = px(style.lookup("margin-right", "margin", &zero));
+        d.margin.top = px(style.lookup("margin-top", "margin", &zero));
+        d.margin.bottom = px(style.lookup("margin-bottom", "margin", &zero));
+
+        d.border.left = px(style.lookup("border-left-width", "border-width", &zero));
+        d.border.right = px(style.lookup("border-right-width", "border-width", &zero));
+        d.border.top = px(style.lookup("border-top-width", "border-width", &zero));
+        d.border.bottom = px(style.lookup("border-bottom-width", "border-width", &zero));
+
+        d.padding.left = px(style.lookup("padding-left", "padding", &zero));
+        d.padding.right = px(style.lookup("padding-right", "padding", &zero));
+        d.padding.top = px(style.lookup("padding-top", "padding", &zero));
+        d.padding.bottom = px(style.lookup("padding-bottom", "padding", &zero));
+    }
+
+    // 置換要素(img)の大きさ。width/height属性はスタイルの計算時にCSSの値に変換されている
+    fn replaced_size(&self, root_font_size: f32) -> Option<(f32, f32)> {
+        let style = self.get_style_node();
+        let elem = style.element()?;
+        if elem.tag_name != "img" {
+            return None;
+        }
+
+        let ctx = self.style_context(root_font_size, 0.0);
+        let size = |name: &str| match style.value(name) {
+            Some(value @ (Length(..) | Value::Calc(_))) => resolve_length(value, &ctx),
+            _ => 0.0,
+        };
+        Some((size("width"), size("height")))
     }
 
-    fn layout_block(&mut self, containing_block: Dimensions) {
+    // 自身と子孫のボックスをまとめて移動する
+    fn translate(&mut self, dx: f32, dy: f32) {
+        self.dimensions.content.x += dx;
+        self.dimensions.content.y += dy;
+        for child in &mut self.children {
+            child.translate(dx, dy);
+        }
+    }
+
+    fn layout_block(
+        &mut self,
+        containing_block: Dimensions,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+        observer: &mut dyn FnMut(&str, &Dimensions),
+    ) {
+        let ctx = self.style_context(root_font_size, containing_block.content.width);
+
         // 子要素の幅は親要素によって決まるので、先に親要素の幅を計算する
-        self.calculate_block_width(containing_block);
+        self.calculate_block_width(&ctx, measurer);
+        observer("width", &self.dimensions);
 
         // コンテナー内のどこに設置するか計算する
-        self.calculate_block_position(containing_block);
+        self.calculate_block_position(containing_block, &ctx);
+        self.dimensions.content.height = 0.0;
+        observer("position", &self.dimensions);
 
-        // 再帰的に子要素もレイアウトする
-        self.layout_block_children();
+        if !self.is_block_flow() {
+            self.layout_children(root_font_size, measurer, observer);
+            self.calculate_block_height(&ctx);
+            observer("height", &self.dimensions);
+            return;
+        }
 
-        // 親要素の高さは子要素の高さによって決まるので子要素が設置された後に高さを計算する
-        self.calculate_block_height();
+        // 深い文書でスタックを使い果たさないよう、入れ子になったブロックは再帰せずに
+        // 明示的なスタックで辿る。下りで幅と位置を、上りで高さを計算する
+        let root = mem::replace(self, LayoutBox::new(AnonymousBlock, self.font_size));
+        let mut stack = vec![open_block(root)];
+        loop {
+            let (parent, pending) = stack.last_mut().unwrap();
+            match pending.next() {
+                Some(mut child) if child.is_block_flow() => {
+                    child.containing_height = parent.content_height(root_font_size);
+                    child.mode = parent.mode;
+                    let ctx = child.style_context(root_font_size, parent.dimensions.content.width);
+                    child.calculate_block_width(&ctx, measurer);
+                    observer("width", &child.dimensions);
+                    child.calculate_block_position(parent.dimensions, &ctx);
+                    child.dimensions.content.height = 0.0;
+                    observer("position", &child.dimensions);
+                    stack.push(open_block(child));
+                }
+                Some(mut child) => {
+                    child.containing_height = parent.content_height(root_font_size);
+                    child.mode = parent.mode;
+                    child.layout(parent.dimensions, root_font_size, measurer, observer);
+                    parent.dimensions.content.height += child.dimensions.margin_box().height;
+                    parent.children.push(child);
+                }
+                None => {
+                    let (mut finished, _) = stack.pop().unwrap();
+                    // 親要素の高さは子要素の高さによって決まるので子要素が設置された後に高さを計算する
+                    // 高さの%は包含ブロックの高さを基準にするので、幅は使わない
+                    let ctx = finished.style_context(root_font_size, 0.0);
+                    finished.calculate_block_height(&ctx);
+                    observer("height", &finished.dimensions);
+                    match stack.last_mut() {
+                        Some((parent, _)) => {
+                            parent.dimensions.content.height +=
+                                finished.dimensions.margin_box().height;
+                            parent.children.push(finished);
+                        }
+                        None => {
+                            *self = finished;
+                            return;
+                        }
+                    }
+                }
+            }
+        }
     }
 
-    fn calculate_block_width(&mut self, containing_block: Dimensions) {
+    // 子要素を縦に積むブロックボックス。フレックスコンテナーと表の行は子要素を横に並べる
+    fn is_block_flow(&self) -> bool {
+        match self.box_type {
+            BlockNode(style) => !matches!(style.display(), Flex | TableRow),
+            InlineNode(_) | AnonymousBlock => false,
+        }
+    }
+
+    fn calculate_block_width(&mut self, ctx: &StyleContext, measurer: &dyn TextMeasurer) {
         let style = self.get_style_node();
+        let px = |v: &Value| resolve_length(v, ctx);
 
-        let auto = Keyword("auto".to_string());
-        let mut width = style.value("width").unwrap_or(auto.clone());
+        let auto = Keyword(Cow::Borrowed("auto"));
+        let mut width = style.value_cloned("width").unwrap_or(auto.clone());
 
         // margin,border, paddginの初期値
         let zero = Length(0.0, Px);
@@ -150,8 +775,38 @@ impl<'a> LayoutBox<'a> {
         let border_left = style.lookup("border-left-width", "border-width", &zero);
         let border_right = style.lookup("border-right-width", "border-width", &zero);
 
-        let mut padding_left = style.lookup("padding-left", "margin", &zero);
-        let mut padding_right = style.lookup("padding-right", "margin", &zero);
+        let mut padding_left = style.lookup("padding-left", "padding", &zero);
+        let mut padding_right = style.lookup("padding-right", "padding", &zero);
+
+        // rtlでは右端が開始側になるので、左右のmarginを入れ替えて余白の配分を反転する
+        let rtl = is_rtl(style);
+        if rtl {
+            mem::swap(&mut margin_left, &mut margin_right);
+        }
+
+        // min-content、max-content、fit-contentは内容の幅から長さを決める
+        if let Keyword(ref keyword) = width {
+            if matches!(&**keyword, "min-content" | "max-content" | "fit-content") {
+                let (min, max) = self.intrinsic_content_widths(ctx.root_font_size, measurer);
+                let edges = [
+                    &margin_left,
+                    &margin_right,
+                    &border_left,
+                    &border_right,
+                    &padding_left,
+                    &padding_right,
+                ];
+                let available = ctx.containing_width - sum(edges.iter().map(|v| px(v)));
+                width = Length(
+                    match &**keyword {
+                        "min-content" => min,
+                        "max-content" => max,
+                        _ => max.min(available).max(min),
+                    },
+                    Px,
+                );
+            }
+        }
 
         let total = sum([
             &margin_left,
@@ -163,10 +818,10 @@ impl<'a> LayoutBox<'a> {
             &width,
         ]
         .iter()
-        .map(|v| v.to_px()));
+        .map(|v| px(v)));
 
         // 子要素の幅が親要素より大きければmarginを0に調整する
-        if width != auto && total > containing_block.content.width {
+        if width != auto && total > ctx.containing_width {
             if margin_left == auto {
                 margin_left = Length(0.0, Px);
             }
@@ -177,29 +832,37 @@ impl<'a> LayoutBox<'a> {
         }
 
         // 空いてるスペース
-        let underflow = containing_block.content.width - total;
+        let underflow = ctx.containing_width - total;
 
         match (width == auto, margin_left == auto, margin_right == auto) {
             // どれもautoではない場合、margin_rightで調整する
             (false, false, false) => {
-                margin_right = Length(margin_right.to_px() + underflow, Px);
+                margin_right = Length(px(&margin_right) + underflow, Px);
             }
 
-            // 左右のmarginのどちらかがautoだった場合、autoになっている箇所で調整する
-            (false, false, true) => {
-                margin_right = Length(underflow, Px);
-            }
-            (false, true, false) => {
-                margin_left = Length(underflow, Px);
+            // 左右のmarginのどちらかがautoだった場合、autoになっている箇所で調整する。
+            // rtlでは配分も反転し、autoでない側で調整する。margin-left: autoなら左に寄る
+            (false, false, true) | (false, true, false) => {
+                let (auto_margin, other_margin) = if margin_left == auto {
+                    (&mut margin_left, &mut margin_right)
+                } else {
+                    (&mut margin_right, &mut margin_left)
+                };
+                if rtl {
+                    *other_margin = Length(px(other_margin) + underflow, Px);
+                    *auto_margin = Length(0.0, Px);
+                } else {
+                    *auto_margin = Length(underflow, Px);
+                }
             }
 
             // widthがautoだったら他の値を0にする
             (true, _, _) => {
                 if margin_left == auto {
-                    margin_left == Length(0.0, Px);
+                    margin_left = Length(0.0, Px);
                 }
                 if margin_right == auto {
-                    margin_right == Length(0.0, Px);
+                    margin_right = Length(0.0, Px);
                 }
 
                 if underflow >= 0.0 {
@@ -208,7 +871,7 @@ impl<'a> LayoutBox<'a> {
                 } else {
                     // 負だった場合はmargin-rightから引いて調整する
                     width = Length(0.0, Px);
-                    margin_right = Length(margin_right.to_px() + underflow, Px)
+                    margin_right = Length(px(&margin_right) + underflow, Px)
                 }
             }
 
@@ -219,39 +882,52 @@ impl<'a> LayoutBox<'a> {
             }
         }
 
+        // 配分が終わったので、marginを物理的な左右に戻す
+        if rtl {
+            mem::swap(&mut margin_left, &mut margin_right);
+        }
+
         let d = &mut self.dimensions;
-        d.content.width = width.to_px();
+        // 幅とpadding、borderは負にならない
+        d.content.width = px(&width).max(0.0);
 
-        d.padding.left = padding_left.to_px();
-        d.padding.right = padding_right.to_px();
+        d.padding.left = px(&padding_left).max(0.0);
+        d.padding.right = px(&padding_right).max(0.0);
 
-        d.border.left = border_left.to_px();
-        d.border.right = border_right.to_px();
+        d.border.left = px(&border_left).max(0.0);
+        d.border.right = px(&border_right).max(0.0);
 
-        d.margin.left = margin_left.to_px();
-        d.margin.right = margin_right.to_px();
+        d.margin.left = px(&margin_left);
+        d.margin.right = px(&margin_right);
     }
 
-    fn calculate_block_position(&mut self, containing_block: Dimensions) {
+    fn calculate_block_position(&mut self, containing_block: Dimensions, ctx: &StyleContext) {
         let style = self.get_style_node();
+        // 上下のmarginとpaddingの%も包含ブロックの幅を基準にする
+        let px = |v: Value| resolve_length(&v, ctx);
         let d = &mut self.dimensions;
 
         let zero = Length(0.0, Px);
 
-        d.margin.top = style.lookup("margin-top", "margin", &zero).to_px();
-        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).to_px();
+        d.margin.top = px(style.lookup("margin-top", "margin", &zero));
+        d.margin.bottom = px(style.lookup("margin-bottom", "margin", &zero));
 
-        d.border.top = style
-            .lookup("border-top-width", "border-width", &zero)
-            .to_px();
-        d.border.bottom = style
-            .lookup("border-bottom-width", "border-width", &zero)
-            .to_px();
+        d.border.top = px(style.lookup("border-top-width", "border-width", &zero));
+        d.border.bottom = px(style.lookup("border-bottom-width", "border-width", &zero));
 
-        d.padding.top = style.lookup("padding-top", "padding", &zero).to_px();
-        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).to_px();
+        d.padding.top = px(style.lookup("padding-top", "padding", &zero));
+        d.padding.bottom = px(style.lookup("padding-bottom", "padding", &zero));
 
-        d.content.x = containing_block.content.x + d.margin.left + d.border.left + d.padding.left;
+        d.content.x = if is_rtl(style) {
+            // rtlでは包含ブロックの右端から測る
+            containing_block.content.x + containing_block.content.width
+                - d.margin.right
+                - d.border.right
+                - d.padding.right
+                - d.content.width
+        } else {
+            containing_block.content.x + d.margin.left + d.border.left + d.padding.left
+        };
         d.content.y = containing_block.content.height
             + containing_block.content.y
             + d.margin.top
@@ -259,55 +935,574 @@ impl<'a> LayoutBox<'a> {
             + d.padding.top;
     }
 
-    fn layout_block_children(&mut self) {
+    fn layout_block_children(
+        &mut self,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+        observer: &mut dyn FnMut(&str, &Dimensions),
+    ) {
+        let containing_height = self.content_height(root_font_size);
+        let mode = self.mode;
         let d = &mut self.dimensions;
         for child in &mut self.children {
-            child.layout(*d);
+            child.containing_height = containing_height;
+            child.mode = mode;
+            child.layout(*d, root_font_size, measurer, observer);
             d.content.height = d.content.height + child.dimensions.margin_box().height;
         }
     }
 
-    fn calculate_block_height(&mut self) {
+    fn layout_children(
+        &mut self,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+        observer: &mut dyn FnMut(&str, &Dimensions),
+    ) {
+        match self.get_style_node().display() {
+            Flex => self.layout_flex_children(root_font_size, measurer, observer),
+            TableRow => self.layout_table_row_children(root_font_size, measurer, observer),
+            _ => self.layout_block_children(root_font_size, measurer, observer),
+        }
+    }
+
+    // 表の行のセルを等しい幅で横に並べる。行の高さは一番高いセルに揃える
+    fn layout_table_row_children(
+        &mut self,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+        observer: &mut dyn FnMut(&str, &Dimensions),
+    ) {
+        let d = self.dimensions;
+        let containing_height = self.content_height(root_font_size);
+        let mode = self.mode;
+        let column_width = d.content.width / self.children.len().max(1) as f32;
+
+        let mut height: f32 = 0.0;
+        for (i, child) in self.children.iter_mut().enumerate() {
+            let mut column = d;
+            column.content.x = d.content.x + column_width * i as f32;
+            column.content.width = column_width;
+            child.containing_height = containing_height;
+            child.mode = mode;
+            child.layout(column, root_font_size, measurer, observer);
+            height = height.max(child.dimensions.margin_box().height);
+        }
+
+        // セルの高さを行に揃え、中身はvertical-alignに従って下にずらす
+        for child in &mut self.children {
+            let extra = height - child.dimensions.margin_box().height;
+            let offset = extra * child.vertical_align_ratio();
+            for grandchild in &mut child.children {
+                grandchild.translate(0.0, offset);
+            }
+            child.dimensions.content.height += extra;
+        }
+        self.dimensions.content.height = height;
+    }
+
+    // 子要素を一行に横並びにする。折り返しはしない
+    fn layout_flex_children(
+        &mut self,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+        observer: &mut dyn FnMut(&str, &Dimensions),
+    ) {
+        let d = self.dimensions;
+        let containing_height = self.content_height(root_font_size);
+        let mode = self.mode;
+        let mut used_width = 0.0;
+        for child in &mut self.children {
+            child.containing_height = containing_height;
+            child.mode = mode;
+            child.layout(d, root_font_size, measurer, observer);
+            child.reset_flex_item_margins(d, root_font_size);
+            // 幅がautoのアイテムは、内容の最大幅を基準の大きさにする
+            if child.has_auto_width() {
+                let (_, max) = child.intrinsic_content_widths(root_font_size, measurer);
+                let edges = child.dimensions.margin_box().width - child.dimensions.content.width;
+                let basis = max.min(d.content.width - edges).max(0.0);
+                let extra_width = basis - child.dimensions.content.width;
+                child.grow_flex_item(extra_width, root_font_size, measurer, observer);
+            }
+            used_width += child.dimensions.margin_box().width;
+        }
+
+        // column-gap(またはgap)はアイテムの間にだけ入れる
+        let style = self.get_style_node();
+        let ctx = self.style_context(root_font_size, d.content.width);
+        let zero = Length(0.0, Px);
+        let gap = resolve_length(&style.lookup("column-gap", "gap", &zero), &ctx).max(0.0);
+        let total_gap = gap * self.children.len().saturating_sub(1) as f32;
+
+        let mut free_space = d.content.width - used_width - total_gap;
+
+        // flex-growが指定されていれば、残りのスペースをその比率で分配する
+        let grow_factors: Vec<f32> = self.children.iter().map(|c| c.flex_grow()).collect();
+        let total_grow: f32 = grow_factors.iter().sum();
+        if total_grow > 0.0 && free_space > 0.0 {
+            for (child, grow) in self.children.iter_mut().zip(grow_factors) {
+                if grow > 0.0 {
+                    child.grow_flex_item(
+                        free_space * grow / total_grow,
+                        root_font_size,
+                        measurer,
+                        observer,
+                    );
+                }
+            }
+            free_space = 0.0;
+        }
+
+        // 残りのスペースをjustify-contentに従って配分する
+        let count = self.children.len();
+        let justify_content = self.get_style_node().value("justify-content");
+        let (start, between) = match justify_content {
+            Some(Keyword(s)) if s == "center" => (free_space / 2.0, 0.0),
+            Some(Keyword(s)) if s == "flex-end" => (free_space, 0.0),
+            Some(Keyword(s)) if s == "space-between" && count > 1 && free_space > 0.0 => {
+                (0.0, free_space / (count - 1) as f32)
+            }
+            _ => (0.0, 0.0),
+        };
+
+        let mut cursor_x = d.content.x + start;
+        let mut height: f32 = 0.0;
+        for child in &mut self.children {
+            let margin_box = child.dimensions.margin_box();
+            child.translate(cursor_x - margin_box.x, 0.0);
+            cursor_x += margin_box.width + between + gap;
+            height = height.max(margin_box.height);
+        }
+        self.dimensions.content.height = height;
+    }
+
+    fn flex_grow(&self) -> f32 {
+        match self.box_type {
+            BlockNode(style) => match style.value("flex-grow") {
+                Some(&Value::Number(n)) if n > 0.0 => n,
+                _ => 0.0,
+            },
+            _ => 0.0,
+        }
+    }
+
+    fn has_auto_width(&self) -> bool {
+        match self.box_type {
+            BlockNode(style) => match style.value("width") {
+                Some(Keyword(width)) => width == "auto",
+                Some(_) => false,
+                None => true,
+            },
+            _ => false,
+        }
+    }
+
+    // 幅を広げて子要素をレイアウトし直す
+    fn grow_flex_item(
+        &mut self,
+        extra_width: f32,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+        observer: &mut dyn FnMut(&str, &Dimensions),
+    ) {
+        self.dimensions.content.width += extra_width;
+        observer("width", &self.dimensions);
+        self.dimensions.content.height = 0.0;
+        self.layout_children(root_font_size, measurer, observer);
+        self.calculate_block_height(&self.style_context(root_font_size, 0.0));
+        observer("height", &self.dimensions);
+    }
+
+    // ブロックのレイアウトでは余ったスペースがmarginに割り当てられるので、指定された値に戻す
+    fn reset_flex_item_margins(&mut self, containing_block: Dimensions, root_font_size: f32) {
+        let style = match self.box_type {
+            BlockNode(style) => style,
+            _ => return,
+        };
+        let ctx = self.style_context(root_font_size, containing_block.content.width);
+        let px = |v: Value| resolve_length(&v, &ctx);
+        let zero = Length(0.0, Px);
+
+        let margin_left = px(style.lookup("margin-left", "margin", &zero));
+        let margin_right = px(style.lookup("margin-right", "margin", &zero));
+        self.translate(margin_left - self.dimensions.margin.left, 0.0);
+        self.dimensions.margin.left = margin_left;
+        self.dimensions.margin.right = margin_right;
+    }
+
+    fn style_context(&self, root_font_size: f32, containing_width: f32) -> StyleContext {
+        StyleContext {
+            containing_width: containing_width,
+            containing_height: self.containing_height,
+            font_size: self.font_size,
+            root_font_size: root_font_size,
+        }
+    }
+
+    /// Return the box's min-content and max-content widths, the narrowest it can be
+    /// laid out without overflowing and the width it takes without any line breaks.
+    /// Both include the box's own margins, borders and padding.
+    pub fn intrinsic_widths(&self, root_font_size: f32, measurer: &dyn TextMeasurer) -> (f32, f32) {
+        let (min, max) = match self.box_type {
+            // 幅が固定されたブロックは内容に関係なくその幅になる
+            BlockNode(style) => match style.value("width") {
+                Some(value @ Length(..)) if !matches!(value, Length(_, Percent)) => {
+                    let width = resolve_length(value, &self.style_context(root_font_size, 0.0));
+                    (width, width)
+                }
+                _ => self.intrinsic_content_widths(root_font_size, measurer),
+            },
+            // 幅が指定されたインラインブロックも同様
+            InlineNode(_) => match self.inline_block_size(root_font_size) {
+                Some((Some(width), _)) => (width, width),
+                _ => self.intrinsic_content_widths(root_font_size, measurer),
+            },
+            AnonymousBlock => self.intrinsic_content_widths(root_font_size, measurer),
+        };
+        let edges = self.horizontal_edges(root_font_size);
+        (min + edges, max + edges)
+    }
+
+    // 内容の最小幅と最大幅。自身のmargin、border、paddingは含まない
+    fn intrinsic_content_widths(
+        &self,
+        root_font_size: f32,
+        measurer: &dyn TextMeasurer,
+    ) -> (f32, f32) {
+        if let Some(text) = self.text() {
+            let max = self.measure_text(text, root_font_size, measurer);
+            let min = text
+                .split_whitespace()
+                .map(|word| self.measure_text(word, root_font_size, measurer))
+                .fold(0.0, f32::max);
+            return (min, max);
+        }
+        if !matches!(self.box_type, AnonymousBlock) {
+            if let Some((width, _)) = self.replaced_size(root_font_size) {
+                return (width, width);
+            }
+        }
+
+        let widths = self
+            .children
+            .iter()
+            .map(|child| child.intrinsic_widths(root_font_size, measurer));
+        match self.box_type {
+            // フレックスアイテムや表のセルは折り返さずに横に並ぶ
+            BlockNode(style) if matches!(style.display(), Flex | TableRow) => widths
+                .fold((0.0, 0.0), |(min, max), (w_min, w_max)| {
+                    (min + w_min, max + w_max)
+                }),
+            // ブロックの子要素は縦に積まれる
+            BlockNode(_) => widths.fold((0.0, 0.0), |(min, max), (w_min, w_max)| {
+                (f32::max(min, w_min), f32::max(max, w_max))
+            }),
+            // インラインの子要素は、最大幅では一行に並び、最小幅ではそれぞれの行に分かれる
+            InlineNode(_) | AnonymousBlock => widths
+                .fold((0.0, 0.0), |(min, max), (w_min, w_max)| {
+                    (f32::max(min, w_min), max + w_max)
+                }),
+        }
+    }
+
+    // 左右のmargin、border、paddingの合計。%やautoは0として扱う
+    fn horizontal_edges(&self, root_font_size: f32) -> f32 {
+        let style = match self.box_type {
+            // テキストは親要素のスタイルを引き継いでいるので、余白を持たない
+            BlockNode(style) | InlineNode(style) if !style.is_text() => style,
+            _ => return 0.0,
+        };
+        let ctx = self.style_context(root_font_size, 0.0);
+        let zero = Length(0.0, Px);
+        let edges = [
+            ("margin-left", "margin"),
+            ("margin-right", "margin"),
+            ("border-left-width", "border-width"),
+            ("border-right-width", "border-width"),
+            ("padding-left", "padding"),
+            ("padding-right", "padding"),
+        ];
+        sum(edges
+            .iter()
+            .map(|&(name, fallback)| resolve_length(&style.lookup(name, fallback, &zero), &ctx)))
+    }
+
+    fn calculate_block_height(&mut self, ctx: &StyleContext) {
         // heightプロパティが設定されていればそれを使う
-        if let Some(Length(h, Px)) = self.get_style_node().value("height") {
-            self.dimensions.content.height = h
+        if let Some(height) = self.resolve_height("height", ctx) {
+            self.dimensions.content.height = height;
+        }
+
+        // min-heightとmax-heightの範囲に収める。両者が矛盾する場合はmin-heightを優先する
+        let max_height = self.resolve_height("max-height", ctx);
+        let min_height = self.resolve_height("min-height", ctx);
+        let d = &mut self.dimensions;
+        if let Some(max_height) = max_height {
+            d.content.height = d.content.height.min(max_height);
+        }
+        if let Some(min_height) = min_height {
+            d.content.height = d.content.height.max(min_height);
+        }
+    }
+
+    // 高さに関するプロパティをpxに変換する。%は包含ブロックの高さが決まっている場合だけ解決できる
+    fn resolve_height(&self, name: &str, ctx: &StyleContext) -> Option<f32> {
+        match self.get_style_node().value(name) {
+            Some(value @ (Length(..) | Value::Calc(_))) => resolve_vertical_length(value, ctx),
+            _ => None,
+        }
+    }
+
+    // 子要素の高さの%の基準になる、自身の指定された高さ。
+    // quirksモードでは高さがautoなら、さらに外側の包含ブロックの高さを使う
+    fn content_height(&self, root_font_size: f32) -> Option<f32> {
+        let height = self.resolve_height("height", &self.style_context(root_font_size, 0.0));
+        match self.mode {
+            Mode::Quirks => height.or(self.containing_height),
+            Mode::Standards => height,
         }
     }
 }
 
 /// Transform a style tree into a layout tree.
-pub fn layout_tree<'a>(
+pub fn layout_tree<'a>(node: &'a StyledNode<'a>, viewport: Viewport) -> LayoutBox<'a> {
+    layout_tree_with_observer(node, viewport, &mut |_, _| {})
+}
+
+/// Like `layout_tree`, but calls `observer` each time a block box's `"width"`,
+/// `"position"` or `"height"` is computed, with the box's dimensions at that point.
+pub fn layout_tree_with_observer<'a>(
+    node: &'a StyledNode<'a>,
+    viewport: Viewport,
+    observer: &mut dyn FnMut(&str, &Dimensions),
+) -> LayoutBox<'a> {
+    // 同じテキストの幅は一度だけ測る
+    let measurer = CachedMeasurer::new(MonospaceMeasurer);
+    layout_root(node, viewport, &measurer, observer)
+}
+
+/// Like `layout_tree`, but measures text with `measurer` instead of the default
+/// cached monospace measurer.
+pub fn layout_tree_with_measurer<'a>(
     node: &'a StyledNode<'a>,
-    mut containing_block: Dimensions,
+    viewport: Viewport,
+    measurer: &dyn TextMeasurer,
+) -> LayoutBox<'a> {
+    layout_root(node, viewport, measurer, &mut |_, _| {})
+}
+
+fn layout_root<'a>(
+    node: &'a StyledNode<'a>,
+    viewport: Viewport,
+    measurer: &dyn TextMeasurer,
+    observer: &mut dyn FnMut(&str, &Dimensions),
 ) -> LayoutBox<'a> {
     // The layout algorithm expects the container height to start at 0.
     // TODO: Save the initial containing block height, for calculating percent heights.
+    let mut containing_block = viewport.dimensions;
     containing_block.content.height = 0.0;
 
-    let mut root_box = build_layout_tree(node);
-    root_box.layout(containing_block);
+    let root_font_size = viewport.root_font_size;
+    let mut root_box = build_layout_tree(node, root_font_size, root_font_size);
+    // quirksモードでは、%の高さは最終的にビューポートの高さを基準にする
+    root_box.mode = viewport.mode;
+    if viewport.mode == Mode::Quirks {
+        root_box.containing_height = Some(viewport.dimensions.content.height);
+    }
+    root_box.layout(containing_block, root_font_size, measurer, observer);
     root_box
 }
 
-fn build_layout_tree<'a>(style_node: &'a StyledNode<'a>) -> LayoutBox<'a> {
-    let mut root = LayoutBox::new(match style_node.display() {
-        Block => BlockNode(style_node),
-        Inline => InlineNode(style_node),
-        DisplayNone => panic!("Root node has display: none"),
-    });
-
-    for child in &style_node.children {
-        match child.display() {
-            Block => root.children.push(build_layout_tree(child)),
-            Inline => root
-                .get_inline_container()
-                .children
-                .push(build_layout_tree(child)),
-            DisplayNone => {}
+/// Lay out the boxes marked with `LayoutBox::mark_dirty` again, reusing the existing
+/// layout of everything else.
+pub fn relayout_tree(root: &mut LayoutBox, viewport: Viewport) {
+    relayout_tree_with_observer(root, viewport, &mut |_, _| {})
+}
+
+/// Like `relayout_tree`, but calls `observer` like `layout_tree_with_observer` for
+/// each dimension that is computed again.
+pub fn relayout_tree_with_observer(
+    root: &mut LayoutBox,
+    viewport: Viewport,
+    observer: &mut dyn FnMut(&str, &Dimensions),
+) {
+    let measurer = CachedMeasurer::new(MonospaceMeasurer);
+    relayout_root(root, viewport, &measurer, observer)
+}
+
+/// Like `relayout_tree`, but measures text with `measurer`, which should be the one
+/// the tree was laid out with. Clean boxes keep the widths measured before, so after
+/// switching measurers (e.g. with `CachedMeasurer::set_measurer`, which also drops the
+/// cached widths) mark the root dirty to measure everything again.
+pub fn relayout_tree_with_measurer(
+    root: &mut LayoutBox,
+    viewport: Viewport,
+    measurer: &dyn TextMeasurer,
+) {
+    relayout_root(root, viewport, measurer, &mut |_, _| {})
+}
+
+fn relayout_root(
+    root: &mut LayoutBox,
+    viewport: Viewport,
+    measurer: &dyn TextMeasurer,
+    observer: &mut dyn FnMut(&str, &Dimensions),
+) {
+    let mut containing_block = viewport.dimensions;
+    containing_block.content.height = 0.0;
+
+    root.relayout(
+        containing_block,
+        viewport.root_font_size,
+        measurer,
+        observer,
+    );
+}
+
+fn build_layout_tree<'a>(
+    style_node: &'a StyledNode<'a>,
+    parent_font_size: f32,
+    root_font_size: f32,
+) -> LayoutBox<'a> {
+    // 深い文書でスタックを使い果たさないよう、再帰せずに明示的なスタックで辿る
+    let root = new_layout_box(style_node, parent_font_size, root_font_size);
+    let mut stack = vec![(root, &style_node.children[..], 0)];
+    loop {
+        let (parent, children, next) = stack.last_mut().unwrap();
+        match next_boxed_child(children, next) {
+            Some(child) => {
+                let child_box = new_layout_box(child, parent.font_size, root_font_size);
+                stack.push((child_box, &child.children[..], 0));
+            }
+            None => {
+                let (mut finished, _, _) = stack.pop().unwrap();
+                finished.merge_anonymous_blocks();
+                let parent = match stack.last_mut() {
+                    Some((parent, _, _)) => parent,
+                    None => return finished,
+                };
+                match finished.box_type {
+                    BlockNode(_) => parent.children.push(finished),
+                    _ => parent.get_inline_container().children.push(finished),
+                }
+            }
+        }
+    }
+}
+
+fn new_layout_box<'a>(
+    style_node: &'a StyledNode<'a>,
+    parent_font_size: f32,
+    root_font_size: f32,
+) -> LayoutBox<'a> {
+    let font_size = match style_node.text() {
+        // テキストは親要素のfont-sizeを使う
+        Some(_) => parent_font_size,
+        None => compute_font_size(style_node, parent_font_size, root_font_size),
+    };
+    LayoutBox::new(
+        match style_node.computed_display() {
+            // フレックスコンテナーや表は外側から見るとブロックボックスとして扱う
+            Block | Flex | Table | TableRow | TableCell => BlockNode(style_node),
+            Inline | InlineBlock => InlineNode(style_node),
+            Display::None => panic!("Root node has display: none"),
+        },
+        font_size,
+    )
+}
+
+// `next`番目以降の子要素のうち、ボックスを作るものを返す
+fn next_boxed_child<'a>(
+    children: &'a [StyledNode<'a>],
+    next: &mut usize,
+) -> Option<&'a StyledNode<'a>> {
+    while let Some(child) = children.get(*next) {
+        let i = *next;
+        *next += 1;
+        // ブロックボックスに隣接する空白だけのテキストは表示されないので、ボックスを作らない。
+        // スタイルの計算時やrestyleで取り除かれているので、ここでは念のために確かめる
+        if is_whitespace_next_to_block(children, i) {
+            continue;
+        }
+        if !matches!(child.computed_display(), Display::None) {
+            return Some(child);
         }
     }
+    None
+}
 
-    root
+// 子要素を取り出し、これから配置する子要素として返す
+fn open_block(mut layout_box: LayoutBox) -> (LayoutBox, vec::IntoIter<LayoutBox>) {
+    let children = mem::take(&mut layout_box.children);
+    (layout_box, children.into_iter())
+}
+
+// 横書きの`direction`だけを扱う。`writing-mode`による縦書きはまだ対応していない
+fn is_rtl(style_node: &StyledNode) -> bool {
+    matches!(style_node.value("direction"), Some(Keyword(direction)) if direction == "rtl")
+}
+
+// font-sizeのemと%は親要素のfont-sizeを基準にする。指定がなければ親要素の値を引き継ぐ。
+// 初期値のmediumはビューポートのroot_font_size(既定では16px)にする
+fn compute_font_size(style_node: &StyledNode, parent_font_size: f32, root_font_size: f32) -> f32 {
+    let ctx = StyleContext {
+        containing_width: parent_font_size,
+        containing_height: None,
+        font_size: parent_font_size,
+        root_font_size: root_font_size,
+    };
+    match style_node.value("font-size") {
+        Some(value @ (Length(..) | Value::Calc(_))) => resolve_length(value, &ctx),
+        Some(Keyword(size)) if size == "medium" => root_font_size,
+        _ => parent_font_size,
+    }
+}
+
+/// What relative lengths are resolved against during layout.
+#[derive(Debug, Clone, Copy)]
+pub struct StyleContext {
+    /// The containing block's width, which most percentages refer to.
+    pub containing_width: f32,
+    /// The containing block's height, if it is specified, for percentage heights.
+    pub containing_height: Option<f32>,
+    /// The element's font size, for `em`, `ex` and `ch`.
+    pub font_size: f32,
+    /// The root element's font size, for `rem`.
+    pub root_font_size: f32,
+}
+
+// これより大きな長さは切り詰め、いくつかの長さを足し合わせても有限の値に収まるようにする
+const MAX_LENGTH: f32 = 1.0e7;
+
+/// Convert a length to px. Values that aren't lengths resolve to 0. Extreme results
+/// are clamped to ±10,000,000px, and NaN becomes 0, so layout arithmetic stays finite.
+pub fn resolve_length(value: &Value, ctx: &StyleContext) -> f32 {
+    resolve_length_against(value, ctx, ctx.containing_width)
+}
+
+/// Like `resolve_length`, but percentages refer to the containing block's height.
+/// Returns `None` if the value has a percentage and that height isn't specified.
+pub fn resolve_vertical_length(value: &Value, ctx: &StyleContext) -> Option<f32> {
+    match ctx.containing_height {
+        Some(height) => Some(resolve_length_against(value, ctx, height)),
+        None if value.has_percentage() => None,
+        None => Some(resolve_length(value, ctx)),
+    }
+}
+
+fn resolve_length_against(value: &Value, ctx: &StyleContext, percent_basis: f32) -> f32 {
+    let px = match *value {
+        Length(f, Percent) => f * percent_basis / 100.0,
+        Value::Calc(ref expr) => {
+            expr.evaluate(&|value| resolve_length_against(value, ctx, percent_basis))
+        }
+        _ => value.to_px_relative(ctx.font_size, ctx.root_font_size),
+    };
+    if px.is_nan() {
+        0.0
+    } else {
+        px.clamp(-MAX_LENGTH, MAX_LENGTH)
+    }
 }
 
 fn sum<I>(iter: I) -> f32
@@ -316,3 +1511,849 @@ where
 {
     iter.fold(0., |a, b| a + b)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{with_layout, with_layout_in, with_style};
+    use crate::text::tests::CountingMeasurer;
+    use crate::{css, dom, style};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+
+    #[test]
+    fn rem_uses_the_viewport_root_font_size() {
+        let mut viewport: Viewport = Default::default();
+        viewport.dimensions.content.width = 200.0;
+        viewport.root_font_size = 20.0;
+        let css = "div { display: block; width: 1rem; }";
+        with_layout_in("<div><div>a</div></div>", css, viewport, |root| {
+            assert_eq!(root.font_size, 20.0);
+            assert_eq!(root.dimensions.content.width, 20.0);
+        });
+    }
+
+    #[test]
+    fn img_attributes_size_an_inline_box() {
+        let html = "<p>ab<img width='100' height='50'/>cd</p>";
+        with_layout(html, "p { display: block; }", 400.0, |root| {
+            let line = &root.children[0];
+            assert_eq!(line.children.len(), 3);
+            let img = line.children[1].dimensions.content;
+            assert_eq!((img.x, img.width, img.height), (16.0, 100.0, 50.0));
+            assert_eq!(line.children[2].dimensions.content.x, 116.0);
+            assert_eq!(root.dimensions.content.height, 50.0);
+        });
+    }
+
+    fn child_xs(parent: &LayoutBox) -> Vec<f32> {
+        parent
+            .children
+            .iter()
+            .map(|child| child.dimensions.content.x)
+            .collect()
+    }
+
+    #[test]
+    fn flex_space_between_spreads_items() {
+        let css = ".row { display: flex; justify-content: space-between; } \
+                   .item { display: block; width: 50px; height: 10px; }";
+        let html = "<div class='row'><div class='item'></div><div class='item'></div>\
+                    <div class='item'></div></div>";
+        with_layout(html, css, 300.0, |row| {
+            assert_eq!(child_xs(row), [0.0, 125.0, 250.0]);
+            assert_eq!(row.dimensions.content.height, 10.0);
+        });
+    }
+
+    #[test]
+    fn flex_grow_shares_free_space_by_factor() {
+        let css = ".row { display: flex; } .item { display: block; width: 0px; height: 10px; } \
+                   .one { flex-grow: 1; } .two { flex-grow: 2; }";
+        let html =
+            "<div class='row'><div class='item one'></div><div class='item two'></div></div>";
+        with_layout(html, css, 300.0, |row| {
+            let widths: Vec<f32> = row
+                .children
+                .iter()
+                .map(|child| child.dimensions.content.width)
+                .collect();
+            assert_eq!(widths, [100.0, 200.0]);
+            assert_eq!(child_xs(row), [0.0, 100.0]);
+        });
+    }
+
+    #[test]
+    fn flex_items_with_auto_width_grow_from_their_content() {
+        let css = ".row { display: flex; } .item { display: block; } \
+                   .one { flex-grow: 1; } .two { flex-grow: 2; }";
+        let html = "<div class='row'><div class='item one'>aaaa</div>\
+                    <div class='item two'>aa</div></div>";
+        with_layout(html, css, 300.0, |row| {
+            let widths: Vec<f32> = row
+                .children
+                .iter()
+                .map(|child| child.dimensions.content.width)
+                .collect();
+            // 内容の幅32pxと16pxに、残りの252pxを1:2で足す
+            assert_eq!(widths, [116.0, 184.0]);
+            assert_eq!(child_xs(row), [0.0, 116.0]);
+        });
+        // flex-growがなければ内容の幅のまま並ぶ
+        let css = ".row { display: flex; } .item { display: block; }";
+        with_layout(html, css, 300.0, |row| {
+            assert_eq!(child_xs(row), [0.0, 32.0]);
+        });
+    }
+
+    #[test]
+    fn relayout_recomputes_ancestor_heights_only() {
+        let html = "<div><div class='a'><div class='leaf'></div></div><div class='b'></div></div>";
+        let before = css::parse("div { display: block; } .leaf, .b { height: 10px; }".to_string());
+        let after = css::parse(
+            "div { display: block; } .leaf { height: 30px; } .b { height: 10px; }".to_string(),
+        );
+        let root = crate::html::parse(html.to_string());
+        let styled_before = style::style_tree(&root, &before, None);
+        let styled_after = style::style_tree(&root, &after, None);
+        let mut viewport: Viewport = Default::default();
+        viewport.dimensions.content.width = 100.0;
+        let mut layout = layout_tree(&styled_before, viewport);
+        assert_eq!(layout.dimensions.content.height, 20.0);
+
+        // スタイルを計算し直した葉のボックスだけを汚す
+        let leaf = &mut layout.children[0].children[0];
+        leaf.box_type = BlockNode(&styled_after.children[0].children[0]);
+        leaf.mark_dirty();
+        let mut events = Vec::new();
+        relayout_tree_with_observer(&mut layout, viewport, &mut |name, d| {
+            events.push((name.to_string(), d.content.height));
+        });
+
+        assert_eq!(layout.dimensions.content.height, 40.0);
+        assert_eq!(layout.children[0].dimensions.content.height, 30.0);
+        assert_eq!(layout.children[1].dimensions.content.y, 30.0);
+        assert!(!layout.children[0].children[0].is_dirty());
+        // 幅を計算し直したのは汚れた葉だけで、兄弟の幅はそのまま
+        let widths = events.iter().filter(|(name, _)| name == "width").count();
+        assert_eq!(widths, 1);
+        let heights: Vec<f32> = events
+            .iter()
+            .filter(|(name, _)| name == "height")
+            .map(|&(_, height)| height)
+            .collect();
+        assert_eq!(heights, [30.0, 30.0, 40.0]);
+    }
+
+    #[test]
+    fn scroll_size_covers_overflowing_children() {
+        let css = ".outer { display: block; width: 100px; height: 50px; } \
+                   .inner { display: block; width: 150px; height: 20px; } \
+                   .tall { display: block; height: 80px; }";
+        with_layout(
+            "<div class='outer'><div class='inner'></div></div>",
+            css,
+            400.0,
+            |outer| {
+                assert_eq!(outer.scroll_size(), (150.0, 50.0));
+            },
+        );
+        with_layout(
+            "<div class='outer'><div class='tall'></div></div>",
+            css,
+            400.0,
+            |outer| {
+                assert_eq!(outer.scroll_size(), (100.0, 80.0));
+            },
+        );
+    }
+
+    #[test]
+    fn before_content_is_the_first_inline_box() {
+        let css = "p { display: block; } p::before { content: \"X\"; }";
+        with_layout("<p>abc</p>", css, 200.0, |p| {
+            let line = &p.children[0];
+            assert!(matches!(line.box_type, AnonymousBlock));
+            let texts: Vec<Option<&str>> = line
+                .children
+                .iter()
+                .map(|b| b.get_style_node().text())
+                .collect();
+            assert_eq!(texts, [Some("X"), Some("abc")]);
+            assert_eq!(line.children[1].dimensions.content.x, 8.0);
+        });
+    }
+
+    #[test]
+    fn text_before_a_block_leaves_no_empty_inline_box() {
+        let css = "div, p { display: block; }";
+        with_layout("<div>text <p>block</p></div>", css, 200.0, |div| {
+            assert_eq!(div.children.len(), 2);
+            assert!(matches!(div.children[0].box_type, AnonymousBlock));
+            assert!(matches!(div.children[1].box_type, BlockNode(_)));
+            assert_eq!(div.children[0].children.len(), 1);
+            let block_line = &div.children[1].children[0];
+            assert!(matches!(block_line.box_type, AnonymousBlock));
+            assert_eq!(block_line.children.len(), 1);
+        });
+    }
+
+    #[test]
+    fn auto_width_with_explicit_height_subtracts_edges() {
+        let css =
+            "div { display: block; width: auto; height: 100px; padding: 10px; border-width: 2px; }";
+        with_layout("<div></div>", css, 200.0, |div| {
+            let d = div.dimensions;
+            assert_eq!(d.content.width, 200.0 - 2.0 * 10.0 - 2.0 * 2.0);
+            assert_eq!(d.content.height, 100.0);
+            assert_eq!(d.border_box().width, 200.0);
+            assert_eq!(d.border_box().height, 124.0);
+            assert_eq!(d.margin.right, 0.0);
+        });
+    }
+
+    fn rect(x: f32, y: f32, width: f32, height: f32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn rect_intersections() {
+        let a = rect(0.0, 0.0, 10.0, 10.0);
+        let overlap = a.intersect(rect(5.0, 5.0, 10.0, 10.0)).unwrap();
+        assert_eq!(
+            (overlap.x, overlap.y, overlap.width, overlap.height),
+            (5.0, 5.0, 5.0, 5.0)
+        );
+        // 辺で接しているだけなら重ならない
+        assert!(a.intersect(rect(10.0, 0.0, 10.0, 10.0)).is_none());
+        assert!(a.intersect(rect(20.0, 20.0, 5.0, 5.0)).is_none());
+    }
+
+    #[test]
+    fn rect_contains_its_top_left_edges_only() {
+        let a = rect(0.0, 0.0, 10.0, 10.0);
+        assert!(a.contains_point(0.0, 0.0));
+        assert!(a.contains_point(5.0, 9.9));
+        assert!(!a.contains_point(10.0, 5.0));
+        assert!(!a.contains_point(5.0, 10.0));
+        assert!(!a.contains_point(-1.0, 5.0));
+    }
+
+    #[test]
+    fn margin_box_expands_by_every_edge() {
+        let edges = |left, right, top, bottom| EdgeSizes {
+            left,
+            right,
+            top,
+            bottom,
+        };
+        let d = Dimensions {
+            content: rect(100.0, 100.0, 50.0, 20.0),
+            padding: edges(1.0, 2.0, 3.0, 4.0),
+            border: edges(5.0, 6.0, 7.0, 8.0),
+            margin: edges(9.0, 10.0, 11.0, 12.0),
+        };
+        let padding = d.padding_box();
+        assert_eq!(
+            (padding.x, padding.y, padding.width, padding.height),
+            (99.0, 97.0, 53.0, 27.0)
+        );
+        let margin = d.margin_box();
+        assert_eq!(
+            (margin.x, margin.y, margin.width, margin.height),
+            (85.0, 79.0, 83.0, 65.0)
+        );
+        assert_eq!(d.content_box().x, 100.0);
+    }
+
+    #[test]
+    fn break_word_splits_a_long_word() {
+        // 20pxの文字は一文字10pxなので、20文字の単語は200px
+        let css = "p { display: block; width: 100px; font-size: 20px; line-height: 20px; \
+                   overflow-wrap: break-word; }";
+        with_layout(&format!("<p>{}</p>", "a".repeat(20)), css, 400.0, |p| {
+            let lines: Vec<(&str, f32, f32)> = p.children[0]
+                .children
+                .iter()
+                .map(|text| {
+                    let content = text.dimensions.content;
+                    (text.text().unwrap(), content.y, content.width)
+                })
+                .collect();
+            let half = "a".repeat(10);
+            assert_eq!(lines, [(&*half, 0.0, 100.0), (&*half, 20.0, 100.0)]);
+            assert_eq!(p.dimensions.content.height, 40.0);
+        });
+    }
+
+    #[test]
+    fn nowrap_keeps_inline_boxes_on_one_line() {
+        let html = "<p><span>aaaa</span><span>bbbb</span><span>cccc</span></p>";
+        let css = "p { display: block; width: 60px; }";
+        with_layout(html, css, 400.0, |p| {
+            assert_eq!(p.dimensions.content.height, 48.0);
+        });
+        let nowrap = format!("{} p {{ white-space: nowrap; }}", css);
+        with_layout(html, &nowrap, 400.0, |p| {
+            let line = &p.children[0];
+            assert_eq!(child_xs(line), [0.0, 32.0, 64.0]);
+            assert!(line
+                .children
+                .iter()
+                .all(|span| span.dimensions.content.y == 0.0));
+            assert_eq!(line.scroll_size().0, 96.0);
+            assert_eq!(p.dimensions.content.height, 16.0);
+        });
+    }
+
+    #[test]
+    fn deeply_nested_blocks_do_not_overflow_the_stack() {
+        let depth = 5000;
+        let mut node = dom::elem("div".to_string(), HashMap::new(), vec![]);
+        for _ in 1..depth {
+            node = dom::elem("div".to_string(), HashMap::new(), vec![node]);
+        }
+        let stylesheet =
+            css::parse("div { display: block; margin-left: 1px; height: 1px; }".to_string());
+        let styled = style::style_tree(&node, &stylesheet, None);
+        let mut viewport: Viewport = Default::default();
+        viewport.dimensions.content.width = 10000.0;
+        let root = layout_tree(&styled, viewport);
+
+        let mut innermost = &root;
+        let mut count = 1;
+        while let Some(child) = innermost.children.first() {
+            innermost = child;
+            count += 1;
+        }
+        assert_eq!(count, depth);
+        assert_eq!(innermost.dimensions.content.x, depth as f32);
+        assert_eq!(root.dimensions.content.height, 1.0);
+    }
+
+    #[test]
+    fn shallow_tree_matches_known_dimensions() {
+        let css = "div { display: block; margin: 5px; padding: 3px; } .leaf { height: 10px; }";
+        let html = "<div><div class='leaf'></div><div><div class='leaf'></div></div></div>";
+        with_layout(html, css, 100.0, |root| {
+            let rects: Vec<(f32, f32, f32, f32)> = [
+                root,
+                &root.children[0],
+                &root.children[1],
+                &root.children[1].children[0],
+            ]
+            .iter()
+            .map(|b| {
+                let c = b.dimensions.content;
+                (c.x, c.y, c.width, c.height)
+            })
+            .collect();
+            assert_eq!(
+                rects,
+                [
+                    (8.0, 8.0, 84.0, 68.0),
+                    (16.0, 16.0, 68.0, 10.0),
+                    (16.0, 42.0, 68.0, 26.0),
+                    (24.0, 50.0, 52.0, 10.0),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn observer_sees_each_step_in_order() {
+        let root = crate::html::parse("<div><div class='inner'></div></div>".to_string());
+        let stylesheet =
+            css::parse("div { display: block; } .inner { margin-top: 5px; }".to_string());
+        let styled = style::style_tree(&root, &stylesheet, None);
+        let mut viewport: Viewport = Default::default();
+        viewport.dimensions.content.width = 100.0;
+        let mut events = Vec::new();
+        layout_tree_with_observer(&styled, viewport, &mut |name, d| {
+            events.push((name.to_string(), d.content.y));
+        });
+        let events: Vec<(&str, f32)> = events.iter().map(|(name, y)| (&**name, *y)).collect();
+        assert_eq!(
+            events,
+            [
+                ("width", 0.0),
+                ("position", 0.0),
+                ("width", 0.0),
+                ("position", 5.0),
+                ("height", 5.0),
+                ("height", 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn min_and_max_height_clamp_auto_height() {
+        // 子要素の高さ30pxが自動の高さになる
+        let html = "<div class='outer'><div class='inner'></div></div>";
+        let base = "div { display: block; } .inner { height: 30px; }";
+        let height = |extra: &str| {
+            let css = format!("{} .outer {{ {} }}", base, extra);
+            with_layout(html, &css, 100.0, |outer| outer.dimensions.content.height)
+        };
+        assert_eq!(height(""), 30.0);
+        assert_eq!(height("min-height: 50px;"), 50.0);
+        assert_eq!(height("max-height: 20px;"), 20.0);
+        assert_eq!(height("min-height: 10px; max-height: 40px;"), 30.0);
+    }
+
+    #[test]
+    fn text_wraps_between_words() {
+        // 一文字8pxなので、60pxの幅には一語ずつしか入らない
+        let css = "p { display: block; width: 60px; font-size: 16px; line-height: 20px; }";
+        with_layout("<p>aaaa bbbb cccc</p>", css, 200.0, |root| {
+            assert_eq!(root.dimensions.content.height, 60.0);
+            let lines: Vec<f32> = root.children[0]
+                .children
+                .iter()
+                .map(|text| text.dimensions.content.y)
+                .collect();
+            assert_eq!(lines, [0.0, 20.0, 40.0]);
+        });
+    }
+
+    #[test]
+    fn long_word_overflows_without_break_word() {
+        let css = "p { display: block; width: 20px; font-size: 16px; line-height: 20px; }";
+        with_layout("<p>aaaaaa b</p>", css, 200.0, |root| {
+            assert_eq!(root.dimensions.content.height, 40.0);
+        });
+    }
+
+    #[test]
+    fn template_content_parses_but_makes_no_boxes() {
+        let html = "<div><template><p>inert</p></template><p>shown</p></div>";
+        let root = crate::html::parse(html.to_string());
+        let template = &root.children[0];
+        assert_eq!(template.children.len(), 1);
+        let inert = &template.children[0].children[0];
+        assert!(matches!(inert.node_type, dom::NodeType::Text(ref text) if text == "inert"));
+
+        with_layout(html, "div, p { display: block; }", 200.0, |div| {
+            assert_eq!(div.children.len(), 1);
+            let p = &div.children[0];
+            assert!(matches!(p.box_type, BlockNode(_)));
+            let text = &p.children[0].children[0];
+            assert!(matches!(text.box_type, InlineNode(_)));
+            assert_eq!(text.text(), Some("shown"));
+        });
+    }
+
+    #[test]
+    fn absolutely_positioned_inline_is_blockified() {
+        let html =
+            "<div><span style='position:absolute;display:inline'>a</span><span>b</span></div>";
+        with_layout(html, "div { display: block; }", 200.0, |div| {
+            assert_eq!(div.children.len(), 2);
+            let BlockNode(span) = div.children[0].box_type else {
+                panic!("expected a block box");
+            };
+            assert_eq!(span.tag_name(), Some("span"));
+            assert!(span.is_block_level());
+            assert!(matches!(div.children[1].box_type, AnonymousBlock));
+        });
+    }
+
+    #[test]
+    fn count_anonymous_blocks_for_mixed_children() {
+        let html = "<div>a<p>b</p>c<span>d</span><p>e</p></div>";
+        with_layout(html, "div, p { display: block; }", 200.0, |div| {
+            // divの中に二つ、各pの中に一つずつ
+            assert_eq!(div.count_boxes(|b| matches!(b, AnonymousBlock)), 4);
+            assert_eq!(div.count_boxes(|b| matches!(b, InlineNode(_))), 6);
+            let first = div.find_first(|b| matches!(b, AnonymousBlock)).unwrap();
+            assert_eq!(first.children[0].text(), Some("a"));
+            assert!(div
+                .find_first(|b| matches!(b, BlockNode(s) if s.tag_name() == Some("em")))
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn huge_lengths_stay_finite() {
+        let css = "div { display: block; width: 1e30px; height: 1e30px; margin-left: -1e30px; \
+                   padding: 1e30px; }";
+        with_layout("<div><div>a</div><div>b</div></div>", css, 200.0, |root| {
+            let mut boxes = vec![root];
+            while let Some(b) = boxes.pop() {
+                let d = b.dimensions;
+                for r in [d.content, d.margin_box()] {
+                    assert!(
+                        r.x.is_finite()
+                            && r.y.is_finite()
+                            && r.width.is_finite()
+                            && r.height.is_finite(),
+                        "{:?}",
+                        d
+                    );
+                }
+                boxes.extend(&b.children);
+            }
+        });
+    }
+
+    #[test]
+    fn flex_gap_goes_between_items() {
+        let css = ".row { display: flex; gap: 10px; } \
+                   .item { display: block; width: 50px; height: 10px; flex-grow: 1; }";
+        let html = "<div class='row'><div class='item'></div><div class='item'></div>\
+                    <div class='item'></div></div>";
+        // 残りの300 - 150 - 20 = 130pxを三等分する
+        with_layout(html, css, 300.0, |row| {
+            let widths: Vec<f32> = row
+                .children
+                .iter()
+                .map(|c| c.dimensions.content.width)
+                .collect();
+            let width = 50.0 + 130.0 / 3.0;
+            assert_eq!(widths, [width; 3]);
+            assert_eq!(child_xs(row), [0.0, width + 10.0, 2.0 * (width + 10.0)]);
+        });
+        let fixed = ".row { display: flex; gap: 10px; } .item { display: block; width: 50px; }";
+        with_layout(html, fixed, 300.0, |row| {
+            assert_eq!(child_xs(row), [0.0, 60.0, 120.0]);
+        });
+    }
+
+    #[test]
+    fn rtl_block_is_placed_from_the_right_edge() {
+        let css = "div { display: block; direction: rtl; width: 100px; }";
+        with_layout("<div></div>", css, 300.0, |root| {
+            assert_eq!(root.dimensions.content.x, 200.0);
+            assert_eq!(root.dimensions.margin.left, 200.0);
+            assert_eq!(root.dimensions.margin.right, 0.0);
+        });
+    }
+
+    #[test]
+    fn rtl_block_keeps_physical_margins() {
+        let css = "div { display: block; direction: rtl; width: 100px; \
+                   margin-left: 10px; margin-right: 20px; }";
+        with_layout("<div></div>", css, 300.0, |root| {
+            assert_eq!(root.dimensions.content.x, 180.0);
+            assert_eq!(root.dimensions.margin.right, 20.0);
+        });
+    }
+
+    #[test]
+    fn rtl_block_auto_margins() {
+        // rtlではautoのmarginの配分が反転し、margin-left: autoのボックスは左に寄る
+        let css = "div { display: block; direction: rtl; width: 100px; margin-left: auto; }";
+        with_layout("<div></div>", css, 300.0, |root| {
+            assert_eq!(root.dimensions.content.x, 0.0);
+            assert_eq!(root.dimensions.margin.left, 0.0);
+            assert_eq!(root.dimensions.margin.right, 200.0);
+        });
+        let css = "div { display: block; direction: rtl; width: 100px; margin-right: auto; }";
+        with_layout("<div></div>", css, 300.0, |root| {
+            assert_eq!(root.dimensions.content.x, 200.0);
+        });
+        let css = "div { display: block; direction: rtl; width: 100px; \
+                   margin-left: auto; margin-right: 20px; }";
+        with_layout("<div></div>", css, 300.0, |root| {
+            assert_eq!(root.dimensions.content.x, 0.0);
+            assert_eq!(root.dimensions.margin.right, 200.0);
+        });
+    }
+
+    #[test]
+    fn font_relative_units() {
+        let css = "div { display: block; font-size: 20px; } .em { width: 2em; } \
+                   .ex { width: 2ex; } .ch { width: 3ch; } .rem { width: 2rem; }";
+        let html = "<div><div class='em'></div><div class='ex'></div><div class='ch'></div>\
+                    <div class='rem'></div></div>";
+        with_layout(html, css, 400.0, |root| {
+            let widths: Vec<f32> = root
+                .children
+                .iter()
+                .map(|child| child.dimensions.content.width)
+                .collect();
+            // exとchはfont-sizeの半分、remはルートのfont-size(16px)を基準にする
+            assert_eq!(widths, [40.0, 20.0, 30.0, 32.0]);
+        });
+    }
+
+    #[test]
+    fn medium_font_size_is_the_root_font_size() {
+        let css = "div { display: block; width: 1em; } .big { font-size: 32px; } \
+                   .medium { font-size: medium; }";
+        let html = "<div><div class='big'><div class='medium'></div></div></div>";
+        let mut viewport: Viewport = Default::default();
+        viewport.dimensions.content.width = 400.0;
+        viewport.root_font_size = 20.0;
+        with_layout_in(html, css, viewport, |root| {
+            // 指定のないルートは初期値のmediumになる
+            assert_eq!(root.dimensions.content.width, 20.0);
+            let big = &root.children[0];
+            assert_eq!(big.dimensions.content.width, 32.0);
+            assert_eq!(big.children[0].dimensions.content.width, 20.0);
+        });
+    }
+
+    #[test]
+    fn border_width_keywords() {
+        let css = "div { display: block; border: medium solid; } \
+                   .thin { border-width: thin; } .thick { border-left-width: thick; }";
+        let html = "<div><div class='thin'></div><div class='thick'></div></div>";
+        with_layout(html, css, 200.0, |root| {
+            assert_eq!(root.dimensions.border.top, 3.0);
+            assert_eq!(root.dimensions.border.left, 3.0);
+            let thin = &root.children[0];
+            assert_eq!(thin.dimensions.border.top, 1.0);
+            let thick = &root.children[1];
+            assert_eq!(thick.dimensions.border.left, 5.0);
+            assert_eq!(thick.dimensions.border.right, 3.0);
+        });
+    }
+
+    #[test]
+    fn adjacent_anonymous_blocks_merge() {
+        with_style("<p>a</p>", "p { display: block; }", |p| {
+            let anonymous = |children| {
+                let mut block = LayoutBox::new(AnonymousBlock, 16.0);
+                block.children = children;
+                block
+            };
+            let leaf = || LayoutBox::new(AnonymousBlock, 16.0);
+            // 間にブロックボックスがあるものはまとめない
+            let mut parent = anonymous(vec![
+                anonymous(vec![leaf()]),
+                anonymous(vec![leaf(), leaf()]),
+                LayoutBox::new(BlockNode(p), 16.0),
+                anonymous(vec![leaf()]),
+            ]);
+            parent.merge_anonymous_blocks();
+            let sizes: Vec<usize> = parent.children.iter().map(|c| c.children.len()).collect();
+            assert_eq!(sizes, [3, 0, 1]);
+            assert!(matches!(parent.children[1].box_type, BlockNode(_)));
+        });
+    }
+
+    #[test]
+    fn table_cells_form_a_grid() {
+        let css = "table { display: table; width: 200px; } tr { display: table-row; } \
+                   td { display: table-cell; height: 20px; }";
+        let html = "<table><tr><td>a</td><td>b</td></tr><tr><td>c</td><td>d</td></tr></table>";
+        with_layout(html, css, 400.0, |table| {
+            let cells: Vec<(f32, f32)> = table
+                .children
+                .iter()
+                .flat_map(|row| &row.children)
+                .map(|cell| (cell.dimensions.content.x, cell.dimensions.content.y))
+                .collect();
+            assert_eq!(
+                cells,
+                [(0.0, 0.0), (100.0, 0.0), (0.0, 20.0), (100.0, 20.0)]
+            );
+            assert_eq!(table.dimensions.content.height, 40.0);
+        });
+    }
+
+    #[test]
+    fn vertical_align_bottom_aligns_inline_block_bottoms() {
+        let css = "p { display: block; } span { display: inline-block; width: 20px; } \
+                   .tall { height: 40px; } .short { height: 10px; vertical-align: bottom; }";
+        let html = "<p><span class='tall'></span><span class='short'></span></p>";
+        with_layout(html, css, 200.0, |root| {
+            let line = &root.children[0];
+            let (tall, short) = (&line.children[0], &line.children[1]);
+            assert_eq!(tall.dimensions.content.height, 40.0);
+            assert_eq!(short.dimensions.content.height, 10.0);
+            assert_eq!(short.dimensions.content.x, 20.0);
+            let bottom =
+                |b: &LayoutBox| b.dimensions.margin_box().y + b.dimensions.margin_box().height;
+            assert_eq!(bottom(short), bottom(tall));
+            assert_eq!(short.dimensions.content.y, 30.0);
+        });
+    }
+
+    #[test]
+    fn text_baseline_is_within_the_font_size() {
+        let css = "p { display: block; font-size: 20px; }";
+        with_layout("<p>abc</p>", css, 200.0, |p| {
+            let text = p.find_first(|b| matches!(b, InlineNode(_))).unwrap();
+            let baseline = text.baseline();
+            assert!(baseline > 10.0 && baseline < 20.0, "{}", baseline);
+            assert_eq!(baseline, 16.0);
+            // ブロックは最後の行のベースラインを使う
+            assert_eq!(p.baseline(), 16.0);
+        });
+    }
+
+    #[test]
+    fn inline_size_maps_to_width() {
+        let logical = with_layout(
+            "<div></div>",
+            "div { display: block; inline-size: 100px; block-size: 30px; }",
+            400.0,
+            |root| {
+                (
+                    root.dimensions.content.width,
+                    root.dimensions.content.height,
+                )
+            },
+        );
+        let physical = with_layout(
+            "<div></div>",
+            "div { display: block; width: 100px; height: 30px; }",
+            400.0,
+            |root| {
+                (
+                    root.dimensions.content.width,
+                    root.dimensions.content.height,
+                )
+            },
+        );
+        assert_eq!(logical, physical);
+        assert_eq!(logical, (100.0, 30.0));
+    }
+
+    #[test]
+    fn hidden_attribute_removes_the_box_unless_overridden() {
+        let css = "div, p { display: block; } p { height: 10px; }";
+        let html = "<div><p hidden=''></p><p hidden='' style='display: block'></p></div>";
+        with_layout(html, css, 100.0, |root| {
+            // 要素セレクタはhidden属性に負けるが、style属性は勝つ
+            assert_eq!(root.children.len(), 1);
+            assert_eq!(root.dimensions.content.height, 10.0);
+        });
+    }
+
+    #[test]
+    fn max_content_width_fits_the_inline_children() {
+        let css = "div { display: block; width: max-content; } \
+                   span { display: inline-block; width: 30px; height: 10px; }";
+        with_layout(
+            "<div><span></span><span></span></div>",
+            css,
+            400.0,
+            |root| {
+                assert_eq!(root.dimensions.content.width, 60.0);
+            },
+        );
+    }
+
+    #[test]
+    fn calc_subtracts_from_the_containing_width() {
+        let css =
+            "div { display: block; width: 200px; } p { display: block; width: calc(100% - 20px); }";
+        with_layout("<div><p></p></div>", css, 400.0, |root| {
+            assert_eq!(root.children[0].dimensions.content.width, 180.0);
+        });
+    }
+
+    #[test]
+    fn style_context_resolves_percent_and_em() {
+        let ctx = StyleContext {
+            containing_width: 200.0,
+            containing_height: Some(50.0),
+            font_size: 10.0,
+            root_font_size: 16.0,
+        };
+        assert_eq!(resolve_length(&Length(25.0, Percent), &ctx), 50.0);
+        assert_eq!(resolve_length(&Length(2.0, css::Unit::Em), &ctx), 20.0);
+        assert_eq!(resolve_length(&Length(2.0, css::Unit::Rem), &ctx), 32.0);
+
+        let css = "div { display: block; width: 200px; } \
+                   p { display: block; font-size: 10px; width: 50%; padding-left: 2em; }";
+        with_layout("<div><p></p></div>", css, 400.0, |root| {
+            let d = root.children[0].dimensions;
+            assert_eq!(d.content.width, 100.0);
+            assert_eq!(d.padding.left, 20.0);
+        });
+    }
+
+    #[test]
+    fn absolute_rect_includes_every_ancestor_offset() {
+        let css = "div { display: block; margin: 10px; padding: 5px; border-width: 2px; }";
+        with_layout("<div><div><div>x</div></div></div>", css, 200.0, |root| {
+            let inner = &root.children[0].children[0];
+            // 各階層で余白10 + 枠2 + 内側5 = 17ずつずれる
+            let rect = inner.absolute_rect();
+            assert_eq!((rect.x, rect.y), (51.0, 51.0));
+            assert_eq!(rect.width, 200.0 - 2.0 * 51.0);
+            let border = inner.absolute_border_rect();
+            assert_eq!((border.x, border.y), (44.0, 44.0));
+            assert_eq!(border.width, rect.width + 2.0 * 7.0);
+            assert_eq!(border.height, rect.height + 2.0 * 7.0);
+        });
+    }
+
+    #[test]
+    fn whitespace_between_blocks_makes_no_boxes() {
+        // html::parseは空白だけのテキストを捨てるので、DOMを直接組み立てる
+        let p = dom::elem("p".to_string(), HashMap::new(), vec![]);
+        let space = || dom::text(" ".to_string());
+        let div = dom::elem("div".to_string(), HashMap::new(), vec![space(), p, space()]);
+        let stylesheet = css::parse("div, p { display: block; }".to_string());
+        let styled = style::style_tree(&div, &stylesheet, None);
+        assert_eq!(styled.children.len(), 1);
+        let root = layout_tree(&styled, Default::default());
+        assert_eq!(root.children.len(), 1);
+        assert!(matches!(root.children[0].box_type, BlockNode(_)));
+        assert!(root.children[0].children.is_empty());
+    }
+
+    #[test]
+    fn repeated_text_runs_are_measured_once() {
+        let root = crate::html::parse(
+            "<p><span>same</span><span>same</span><span>same</span></p>".to_string(),
+        );
+        let stylesheet = css::parse("p { display: block; }".to_string());
+        let styled = style::style_tree(&root, &stylesheet, None);
+        let mut viewport: Viewport = Default::default();
+        viewport.dimensions.content.width = 800.0;
+        let calls = Cell::new(0);
+        let measurer = CachedMeasurer::new(CountingMeasurer { calls: &calls });
+        let layout = layout_tree_with_measurer(&styled, viewport, &measurer);
+
+        let widths: Vec<f32> = layout
+            .children
+            .iter()
+            .flat_map(|line| &line.children)
+            .map(|span| span.dimensions.content.width)
+            .collect();
+        assert_eq!(widths.len(), 3);
+        assert!(widths
+            .iter()
+            .all(|&width| width == widths[0] && width > 0.0));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn relayout_shares_the_measurer_cache() {
+        let root = crate::html::parse("<div><p>same</p><p>same</p></div>".to_string());
+        let stylesheet = css::parse("div, p { display: block; }".to_string());
+        let styled = style::style_tree(&root, &stylesheet, None);
+        let mut viewport: Viewport = Default::default();
+        viewport.dimensions.content.width = 800.0;
+        let calls = Cell::new(0);
+        let mut measurer = CachedMeasurer::new(CountingMeasurer { calls: &calls });
+        let mut layout = layout_tree_with_measurer(&styled, viewport, &measurer);
+        assert_eq!(calls.get(), 1);
+
+        // 汚れた段落のテキストはキャッシュから引けるので測り直さない
+        layout.children[1].mark_dirty();
+        relayout_tree_with_measurer(&mut layout, viewport, &measurer);
+        assert_eq!(calls.get(), 1);
+
+        // 測り方を替えたらキャッシュは捨てられ、新しい測り方で測り直す
+        let new_calls = Cell::new(0);
+        measurer.set_measurer(CountingMeasurer { calls: &new_calls });
+        layout.mark_dirty();
+        relayout_tree_with_measurer(&mut layout, viewport, &measurer);
+        assert_eq!(new_calls.get(), 1);
+        assert_eq!(calls.get(), 1);
+        let text = &layout.children[0].children[0].children[0];
+        assert_eq!(text.dimensions.content.width, 32.0);
+    }
+}
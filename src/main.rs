@@ -10,9 +10,14 @@ pub mod css;
 pub mod dom;
 pub mod html;
 pub mod layout;
+#[cfg(feature = "net")]
+pub mod net;
 pub mod painting;
 pub mod pdf;
 pub mod style;
+#[cfg(test)]
+pub mod test_util;
+pub mod text;
 
 fn main() {
     // Parse command-line options:
@@ -21,6 +26,7 @@ fn main() {
     opts.optopt("c", "css", "CSS stylesheet", "FILENAME");
     opts.optopt("o", "output", "Output file", "FILENAME");
     opts.optopt("f", "format", "Output file format", "png | pdf");
+    opts.optopt("s", "scale", "Device pixels per CSS pixel", "NUMBER");
 
     let matches = opts.parse(std::env::args().skip(1)).unwrap();
     let str_arg = |flag: &str, default: &str| -> String {
@@ -34,19 +40,27 @@ fn main() {
         x => panic!("Unknown output format: {}", x),
     };
 
+    let scale: f32 = str_arg("s", "1").parse().expect("Invalid scale");
+
     // Read input files:
     let html = read_source(str_arg("h", "examples/test.html"));
     let css = read_source(str_arg("c", "examples/test.css"));
 
     // Since we don't have an actual window, hard-code the "viewport" size.
-    let mut viewport: layout::Dimensions = Default::default();
-    viewport.content.width = 800.0;
-    viewport.content.height = 600.0;
+    let mut viewport: layout::Viewport = Default::default();
+    viewport.dimensions.content.width = 800.0;
+    viewport.dimensions.content.height = 600.0;
 
     // Parsing and rendering:
-    let root_node = html::parse(html);
+    let document = html::parse_document(html);
+    viewport.mode = document.mode();
+    let root_node = document.root;
     let stylesheet = css::parse(css);
-    let style_root = style::style_tree(&root_node, &stylesheet, Some(&HashMap::new()));
+    // Stylesheets embedded in the document come after the external one.
+    let embedded_stylesheets = dom::extract_stylesheets(&root_node);
+    let mut stylesheets = vec![&stylesheet];
+    stylesheets.extend(embedded_stylesheets.iter());
+    let style_root = style::style_tree_multi(&root_node, &stylesheets, Some(&HashMap::new()));
     let layout_root = layout::layout_tree(&style_root, viewport);
 
     // Create the output file:
@@ -55,7 +69,7 @@ fn main() {
 
     // Write to the file:
     let ok = if png {
-        let canvas = painting::paint(&layout_root, viewport.content);
+        let canvas = painting::paint(&layout_root, viewport.dimensions.content, scale);
         let (w, h) = (canvas.width as u32, canvas.height as u32);
         let img = image::ImageBuffer::from_fn(w, h, move |x, y| {
             let color = canvas.pixels[(y * w + x) as usize];
@@ -63,7 +77,7 @@ fn main() {
         });
         image::ImageRgba8(img).save(&mut file, image::PNG).is_ok()
     } else {
-        pdf::render(&layout_root, viewport.content, &mut file).is_ok()
+        pdf::render(&layout_root, viewport.dimensions.content, &mut file).is_ok()
     };
     if ok {
         println!("Saved output as {}", filename)
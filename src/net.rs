@@ -0,0 +1,255 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::html;
+
+#[derive(Debug)]
+pub enum FetchError {
+    InvalidUrl(String),
+    Io(io::Error),
+    InvalidResponse,
+    Status(u16),
+    TooManyRedirects,
+}
+
+impl From<io::Error> for FetchError {
+    fn from(err: io::Error) -> FetchError {
+        FetchError::Io(err)
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FetchError::InvalidUrl(ref url) => write!(f, "invalid url: {}", url),
+            FetchError::Io(ref err) => write!(f, "{}", err),
+            FetchError::InvalidResponse => write!(f, "invalid HTTP response"),
+            FetchError::Status(status) => write!(f, "unexpected HTTP status {}", status),
+            FetchError::TooManyRedirects => write!(f, "too many redirects"),
+        }
+    }
+}
+
+/// Fetch `url` with a blocking HTTP/1.1 GET and return the response body.
+/// Only plain `http://` URLs are supported. A single redirect is followed.
+pub fn fetch(url: &str) -> Result<String, FetchError> {
+    let url = Url::parse(url)?;
+    let response = get(&url)?;
+    let response = match response.status {
+        301 | 302 | 303 | 307 | 308 => {
+            let location = response.location.ok_or(FetchError::InvalidResponse)?;
+            let response = get(&url.join(&location)?)?;
+            if (300..400).contains(&response.status) {
+                return Err(FetchError::TooManyRedirects);
+            }
+            response
+        }
+        _ => response,
+    };
+    if !(200..300).contains(&response.status) {
+        return Err(FetchError::Status(response.status));
+    }
+    Ok(html::decode_html(&response.body))
+}
+
+struct Url {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url, FetchError> {
+        let invalid = || FetchError::InvalidUrl(url.to_string());
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => (
+                &authority[..i],
+                authority[i + 1..].parse().map_err(|_| invalid())?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Url {
+            host: host.to_string(),
+            port: port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Resolve a `Location` header, which may be absolute or relative to this host.
+    fn join(&self, location: &str) -> Result<Url, FetchError> {
+        if location.starts_with("http://") {
+            return Url::parse(location);
+        }
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let dir = &self.path[..self.path.rfind('/').unwrap_or(0) + 1];
+            format!("{}{}", dir, location)
+        };
+        Ok(Url {
+            host: self.host.clone(),
+            port: self.port,
+            path: path,
+        })
+    }
+}
+
+struct Response {
+    status: u16,
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+fn get(url: &Url) -> Result<Response, FetchError> {
+    let mut stream = TcpStream::connect((&*url.host, url.port))?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        url.path, url.host
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw)?;
+    parse_response(&raw)
+}
+
+fn parse_response(raw: &[u8]) -> Result<Response, FetchError> {
+    let header_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(FetchError::InvalidResponse)?;
+    let head = String::from_utf8_lossy(&raw[..header_end]);
+    let mut body = &raw[header_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or(FetchError::InvalidResponse)?;
+
+    let mut location = None;
+    let mut chunked = false;
+    for line in lines {
+        let (name, value) = match line.find(':') {
+            Some(i) => (line[..i].trim().to_ascii_lowercase(), line[i + 1..].trim()),
+            None => continue,
+        };
+        match &*name {
+            "location" => location = Some(value.to_string()),
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "content-length" => {
+                let length: usize = value.parse().map_err(|_| FetchError::InvalidResponse)?;
+                body = &body[..length.min(body.len())];
+            }
+            _ => {}
+        }
+    }
+
+    let body = if chunked {
+        decode_chunked(body)?
+    } else {
+        body.to_vec()
+    };
+    Ok(Response {
+        status: status,
+        location: location,
+        body: body,
+    })
+}
+
+fn decode_chunked(mut input: &[u8]) -> Result<Vec<u8>, FetchError> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = input
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or(FetchError::InvalidResponse)?;
+        let size_line = String::from_utf8_lossy(&input[..line_end]);
+        // Chunk extensions after `;` are ignored.
+        let size_hex = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16).map_err(|_| FetchError::InvalidResponse)?;
+        input = &input[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if input.len() < size {
+            return Err(FetchError::InvalidResponse);
+        }
+        body.extend_from_slice(&input[..size]);
+        input = input.get(size + 2..).unwrap_or(&[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // 受け取った要求のパスごとに、決まった応答を返すサーバーを立てる
+    fn serve(responses: Vec<(&'static str, &'static str)>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(responses.len()) {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                BufReader::new(&stream)
+                    .read_line(&mut request_line)
+                    .unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or("");
+                let response = responses
+                    .iter()
+                    .find(|&&(p, _)| p == path)
+                    .map_or("HTTP/1.1 404 Not Found\r\n\r\n", |&(_, r)| r);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn fetches_a_fixed_body() {
+        let port = serve(vec![(
+            "/index.html",
+            "HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\n<p>hi</p>\r\n",
+        )]);
+        let body = fetch(&format!("http://127.0.0.1:{}/index.html", port)).unwrap();
+        assert_eq!(body, "<p>hi</p>\r\n");
+    }
+
+    #[test]
+    fn follows_a_redirect_to_a_chunked_body() {
+        let port = serve(vec![
+            ("/old", "HTTP/1.1 301 Moved\r\nLocation: new\r\n\r\n"),
+            (
+                "/new",
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+            ),
+        ]);
+        let body = fetch(&format!("http://127.0.0.1:{}/old", port)).unwrap();
+        assert_eq!(body, "abcde");
+    }
+
+    #[test]
+    fn error_status_is_reported() {
+        let port = serve(vec![("/index.html", "HTTP/1.1 200 OK\r\n\r\n")]);
+        let url = format!("http://127.0.0.1:{}/missing", port);
+        assert!(matches!(fetch(&url), Err(FetchError::Status(404))));
+        assert!(matches!(
+            fetch("ftp://example.com/"),
+            Err(FetchError::InvalidUrl(_))
+        ));
+    }
+}
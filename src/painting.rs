@@ -10,16 +10,29 @@ type DisplayList = Vec<DisplayCommand>;
 
 pub enum DisplayCommand {
     SolidColor(Color, Rect),
+    // 画像を矩形の左上から敷き詰める
+    Image(Image, Rect),
+}
+
+/// An RGBA image, stored row by row from the top left.
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
 }
 
 pub struct Canvas {
     pub pixels: Vec<Color>,
     pub width: usize,
     pub height: usize,
+    // CSSピクセル1つあたりのデバイスピクセル数
+    pub scale: f32,
 }
 
 impl Canvas {
-    fn new(width: usize, height: usize) -> Canvas {
+    // widthとheightはデバイスピクセル単位
+    fn new(width: usize, height: usize, scale: f32) -> Canvas {
         let white = Color {
             r: 255,
             g: 255,
@@ -30,31 +43,88 @@ impl Canvas {
             pixels: repeat(white).take(width * height).collect(),
             width: width,
             height: height,
+            scale: scale,
         }
     }
 
     fn paint_item(&mut self, item: &DisplayCommand) {
         match item {
             &DisplayCommand::SolidColor(color, rect) => {
-                // 四角形の境界線
-                let x0 = rect.x.clamp(0.0, self.width as f32) as usize;
-                let y0 = rect.y.clamp(0.0, self.height as f32) as usize;
-                let x1 = (rect.x + rect.width).clamp(0.0, self.width as f32) as usize;
-                let y1 = (rect.y + rect.height).clamp(0.0, self.height as f32) as usize;
-
+                let (x0, y0, x1, y1) = self.pixel_bounds(rect);
                 for y in (y0..y1) {
                     for x in (x0..x1) {
-                        self.pixels[x + y * self.width] = color
+                        let pixel = &mut self.pixels[x + y * self.width];
+                        *pixel = blend(color, *pixel)
+                    }
+                }
+            }
+            &DisplayCommand::Image(ref image, rect) => {
+                if image.width == 0 || image.height == 0 {
+                    return;
+                }
+                let (x0, y0, x1, y1) = self.pixel_bounds(rect);
+                for y in y0..y1 {
+                    let image_y = (y as f32 / self.scale - rect.y) as usize % image.height;
+                    for x in x0..x1 {
+                        let image_x = (x as f32 / self.scale - rect.x) as usize % image.width;
+                        let color = image.pixels[image_x + image_y * image.width];
+                        let pixel = &mut self.pixels[x + y * self.width];
+                        *pixel = blend(color, *pixel)
                     }
                 }
             }
         }
     }
+
+    // 四角形の境界線を拡大し、キャンバスの範囲に収めたデバイスピクセル座標
+    fn pixel_bounds(&self, rect: Rect) -> (usize, usize, usize, usize) {
+        let scale = self.scale;
+        let x0 = (rect.x * scale).clamp(0.0, self.width as f32) as usize;
+        let y0 = (rect.y * scale).clamp(0.0, self.height as f32) as usize;
+        let x1 = ((rect.x + rect.width) * scale).clamp(0.0, self.width as f32) as usize;
+        let y1 = ((rect.y + rect.height) * scale).clamp(0.0, self.height as f32) as usize;
+        (x0, y0, x1, y1)
+    }
+}
+
+// 半透明の色を背景色に重ねる
+fn blend(src: Color, dst: Color) -> Color {
+    if src.a == 255 {
+        return src;
+    }
+    let alpha = src.a as f32 / 255.0;
+    let mix = |s: u8, d: u8| (s as f32 * alpha + d as f32 * (1.0 - alpha)).round() as u8;
+    Color {
+        r: mix(src.r, dst.r),
+        g: mix(src.g, dst.g),
+        b: mix(src.b, dst.b),
+        a: (src.a as f32 + dst.a as f32 * (1.0 - alpha)).round() as u8,
+    }
+}
+
+/// Paint the layout tree into a canvas of `bounds`, which is in CSS pixels. The canvas
+/// has `scale` device pixels per CSS pixel, e.g. twice the resolution for `scale = 2`.
+pub fn paint(layout_root: &LayoutBox, bounds: Rect, scale: f32) -> Canvas {
+    paint_with_images(layout_root, bounds, scale, |_| None)
 }
 
-pub fn paint(layout_root: &LayoutBox, bounds: Rect) -> Canvas {
-    let display_list = build_display_list(layout_root);
-    let mut canvas = Canvas::new(bounds.width as usize, bounds.height as usize);
+/// Like `paint`, but draws `background-image`s, calling `image_loader` with each URL.
+/// Boxes whose image can't be loaded only get their background color.
+pub fn paint_with_images<F>(
+    layout_root: &LayoutBox,
+    bounds: Rect,
+    scale: f32,
+    image_loader: F,
+) -> Canvas
+where
+    F: Fn(&str) -> Option<Image>,
+{
+    let display_list = build_display_list_with_images(layout_root, &image_loader);
+    let mut canvas = Canvas::new(
+        (bounds.width * scale) as usize,
+        (bounds.height * scale) as usize,
+        scale,
+    );
     for item in display_list {
         canvas.paint_item(&item);
     }
@@ -62,33 +132,183 @@ pub fn paint(layout_root: &LayoutBox, bounds: Rect) -> Canvas {
 }
 
 pub fn build_display_list(layout_root: &LayoutBox) -> DisplayList {
-    let mut list = Vec::new();
-    render_layout_box(&mut list, layout_root);
-    list
+    build_display_list_with_images(layout_root, &|_| None)
 }
 
-fn render_layout_box(list: &mut DisplayList, layout_box: &LayoutBox) {
-    render_background(list, layout_box);
-    render_borders(list, layout_box);
+pub fn build_display_list_with_images(
+    layout_root: &LayoutBox,
+    image_loader: &dyn Fn(&str) -> Option<Image>,
+) -> DisplayList {
+    let mut layers = Vec::new();
+    render_layout_box(&mut layers, layout_root, image_loader, 1.0, 0);
+
+    // z-indexの小さい順に描画する。同じ値のものはツリーの順番を保つ
+    layers.sort_by_key(|&(z_index, _)| z_index);
+    layers.into_iter().map(|(_, item)| item).collect()
+}
+
+fn render_layout_box(
+    layers: &mut Vec<(i32, DisplayCommand)>,
+    layout_box: &LayoutBox,
+    image_loader: &dyn Fn(&str) -> Option<Image>,
+    parent_opacity: f32,
+    parent_z_index: i32,
+) {
+    // opacityは親要素の値と掛け合わせて子孫要素に引き継ぐ
+    let opacity = parent_opacity * get_opacity(layout_box);
+
+    // z-indexが指定されていなければ親要素と同じ層に描画する
+    let z_index = get_z_index(layout_box).unwrap_or(parent_z_index);
+
+    // visibility: hiddenの場合は自身を描画しないが、子要素は描画する
+    if is_visible(layout_box) {
+        let mut list = Vec::new();
+        // テキストは親要素のスタイルを引き継いでいるので、背景やborderは描画しない
+        if is_text(layout_box) {
+            render_text_decoration(&mut list, layout_box);
+        } else {
+            render_background(&mut list, layout_box, image_loader);
+            render_borders(&mut list, layout_box);
+            render_outline(&mut list, layout_box);
+        }
+        apply_opacity(&mut list, opacity);
+        layers.extend(list.into_iter().map(|item| (z_index, item)));
+    }
 
     for child in &layout_box.children {
-        render_layout_box(list, child)
+        render_layout_box(layers, child, image_loader, opacity, z_index)
     }
 }
 
-fn render_background(list: &mut DisplayList, layout_box: &LayoutBox) {
-    get_color(layout_box, "background").map(|color| {
+// z-indexは位置指定された要素にのみ適用される
+fn get_z_index(layout_box: &LayoutBox) -> Option<i32> {
+    match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) => {
+            let positioned = matches!(
+                style.value("position"),
+                Some(Value::Keyword(position)) if position != "static"
+            );
+            match style.value("z-index") {
+                Some(&Value::Number(n)) if positioned => Some(n as i32),
+                _ => None,
+            }
+        }
+        AnonymousBlock => None,
+    }
+}
+
+fn get_opacity(layout_box: &LayoutBox) -> f32 {
+    match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) => style
+            .value("opacity")
+            .and_then(Value::as_number)
+            .map_or(1.0, |n| n.clamp(0.0, 1.0)),
+        AnonymousBlock => 1.0,
+    }
+}
+
+fn apply_opacity(items: &mut [DisplayCommand], opacity: f32) {
+    if opacity >= 1.0 {
+        return;
+    }
+    for item in items {
+        match item {
+            DisplayCommand::SolidColor(color, _) => {
+                color.a = (color.a as f32 * opacity).round() as u8;
+            }
+            DisplayCommand::Image(image, _) => {
+                for color in &mut image.pixels {
+                    color.a = (color.a as f32 * opacity).round() as u8;
+                }
+            }
+        }
+    }
+}
+
+fn render_background(
+    list: &mut DisplayList,
+    layout_box: &LayoutBox,
+    image_loader: &dyn Fn(&str) -> Option<Image>,
+) {
+    let color = get_color(layout_box, "background-color");
+    color.map(|color| {
         list.push(DisplayCommand::SolidColor(
             color,
             layout_box.dimensions.border_box(),
         ))
     });
+
+    // 背景画像は背景色の上に、コンテンツ領域に敷き詰めて描画する
+    let url = match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) => {
+            style.value("background-image").and_then(Value::as_url)
+        }
+        AnonymousBlock => None,
+    };
+    if let Some(image) = url.and_then(image_loader) {
+        list.push(DisplayCommand::Image(image, layout_box.dimensions.content));
+    }
+}
+
+fn is_text(layout_box: &LayoutBox) -> bool {
+    match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) => style.is_text(),
+        AnonymousBlock => false,
+    }
+}
+
+fn is_visible(layout_box: &LayoutBox) -> bool {
+    match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) => {
+            matches!(style.visibility(), style::Visibility::Visible)
+        }
+        AnonymousBlock => true,
+    }
 }
 
+fn render_text_decoration(list: &mut DisplayList, layout_box: &LayoutBox) {
+    let underline = match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) => matches!(
+            style.value("text-decoration"),
+            Some(Value::Keyword(decoration)) if decoration == "underline"
+        ),
+        AnonymousBlock => false,
+    };
+    if !underline {
+        return;
+    }
+
+    let black = Color {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+    let color = get_color(layout_box, "color").unwrap_or(black);
+    let content = layout_box.dimensions.content;
+
+    // ベースラインの位置に1pxの線を引く
+    list.push(DisplayCommand::SolidColor(
+        color,
+        Rect {
+            x: content.x,
+            y: content.y + layout_box.baseline(),
+            width: content.width,
+            height: 1.0,
+        },
+    ));
+}
+
+// currentColorは要素のcolorの値で塗る
 fn get_color(layout_box: &LayoutBox, name: &str) -> Option<Color> {
     match layout_box.box_type {
         BlockNode(style) | InlineNode(style) => match style.value(name) {
-            Some(Value::ColorValue(color)) => Some(color),
+            Some(&Value::ColorValue(color)) => Some(color),
+            Some(Value::Keyword(keyword))
+                if keyword.eq_ignore_ascii_case("currentcolor") && name != "color" =>
+            {
+                get_color(layout_box, "color")
+            }
             _ => None,
         },
         AnonymousBlock => None,
@@ -96,57 +316,133 @@ fn get_color(layout_box: &LayoutBox, name: &str) -> Option<Color> {
 }
 
 fn render_borders(list: &mut DisplayList, layout_box: &LayoutBox) {
-    let color = match get_color(layout_box, "border-color") {
-        Some(color) => color,
-        _ => return,
+    let style = match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) => style,
+        AnonymousBlock => return,
+    };
+
+    // border-styleの初期値はnoneで、その場合は太さが指定されていてもborderを描画しない。
+    // 各辺のスタイルや色が指定されていなければborder-styleとborder-colorを使う
+    let none = Value::keyword("none");
+    let border_color = get_color(layout_box, "border-color");
+    let side_color = |side: &str| {
+        let has_style = matches!(
+            style.lookup(&format!("border-{}-style", side), "border-style", &none),
+            Value::Keyword(border_style) if border_style != "none" && border_style != "hidden"
+        );
+        if !has_style {
+            return None;
+        }
+        get_color(layout_box, &format!("border-{}-color", side)).or(border_color)
     };
 
     let d = &layout_box.dimensions;
     let border_box = d.border_box();
 
     // 左のborder
-    list.push(DisplayCommand::SolidColor(
-        color,
-        Rect {
-            x: border_box.x,
-            y: border_box.y,
-            width: d.border.left,
-            height: border_box.height,
-        },
-    ));
+    if let Some(color) = side_color("left") {
+        list.push(DisplayCommand::SolidColor(
+            color,
+            Rect {
+                x: border_box.x,
+                y: border_box.y,
+                width: d.border.left,
+                height: border_box.height,
+            },
+        ));
+    }
 
     // 右のborder
-    list.push(DisplayCommand::SolidColor(
-        color,
-        Rect {
-            x: border_box.x + border_box.width - d.border.right,
-            y: border_box.y,
-            width: d.border.right,
-            height: border_box.height,
-        },
-    ));
+    if let Some(color) = side_color("right") {
+        list.push(DisplayCommand::SolidColor(
+            color,
+            Rect {
+                x: border_box.x + border_box.width - d.border.right,
+                y: border_box.y,
+                width: d.border.right,
+                height: border_box.height,
+            },
+        ));
+    }
 
     // 上のborder
-    list.push(DisplayCommand::SolidColor(
-        color,
-        Rect {
-            x: border_box.x,
-            y: border_box.y,
-            width: border_box.width,
-            height: d.border.top,
-        },
-    ));
+    if let Some(color) = side_color("top") {
+        list.push(DisplayCommand::SolidColor(
+            color,
+            Rect {
+                x: border_box.x,
+                y: border_box.y,
+                width: border_box.width,
+                height: d.border.top,
+            },
+        ));
+    }
 
     // 下のborder
-    list.push(DisplayCommand::SolidColor(
-        color,
-        Rect {
-            x: border_box.x,
-            y: border_box.y + border_box.height - d.border.bottom,
-            width: border_box.width,
-            height: d.border.bottom,
-        },
-    ));
+    if let Some(color) = side_color("bottom") {
+        list.push(DisplayCommand::SolidColor(
+            color,
+            Rect {
+                x: border_box.x,
+                y: border_box.y + border_box.height - d.border.bottom,
+                width: border_box.width,
+                height: d.border.bottom,
+            },
+        ));
+    }
+}
+
+// outlineはborderの外側に描画し、レイアウトには影響しない。親要素からはみ出すこともある
+fn render_outline(list: &mut DisplayList, layout_box: &LayoutBox) {
+    let style = match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) => style,
+        AnonymousBlock => return,
+    };
+    if matches!(
+        style.value("outline-style"),
+        Some(Value::Keyword(outline_style)) if outline_style == "none" || outline_style == "hidden"
+    ) {
+        return;
+    }
+    let width = style.value("outline-width").map_or(0.0, Value::to_px);
+    let color = match get_color(layout_box, "outline-color") {
+        Some(color) if width > 0.0 => color,
+        _ => return,
+    };
+
+    let border_box = layout_box.dimensions.border_box();
+    let outer_x = border_box.x - width;
+    let outer_y = border_box.y - width;
+    let outer_width = border_box.width + width * 2.0;
+
+    // 上下の線は角を含めて描き、左右の線はその間を埋める
+    let sides = [
+        (outer_x, outer_y, outer_width, width),
+        (
+            outer_x,
+            border_box.y + border_box.height,
+            outer_width,
+            width,
+        ),
+        (outer_x, border_box.y, width, border_box.height),
+        (
+            border_box.x + border_box.width,
+            border_box.y,
+            width,
+            border_box.height,
+        ),
+    ];
+    for &(x, y, width, height) in &sides {
+        list.push(DisplayCommand::SolidColor(
+            color,
+            Rect {
+                x: x,
+                y: y,
+                width: width,
+                height: height,
+            },
+        ));
+    }
 }
 
 trait Clamp {
@@ -157,3 +453,177 @@ impl Clamp for f32 {
         self.max(lower).min(upper)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Viewport;
+    use crate::test_util::{render, with_layout_in};
+
+    const WHITE: Color = Color {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 255,
+    };
+    const RED: Color = Color {
+        r: 255,
+        g: 0,
+        b: 0,
+        a: 255,
+    };
+
+    fn pixel(canvas: &Canvas, x: usize, y: usize) -> Color {
+        canvas.pixels[x + y * canvas.width]
+    }
+
+    #[test]
+    fn hidden_box_reserves_space_but_paints_nothing() {
+        let css = "div { display: block; height: 20px; } \
+                   .hidden { visibility: hidden; background: #ff0000; } \
+                   .shown { background: #ff0000; }";
+        let html = "<div><div class='hidden'></div><div class='shown'></div></div>";
+        let canvas = render(html, css, 40.0, 60.0);
+        assert_eq!(pixel(&canvas, 5, 10), WHITE);
+        assert_eq!(pixel(&canvas, 5, 30), RED);
+        assert_eq!(pixel(&canvas, 5, 50), WHITE);
+    }
+
+    #[test]
+    fn opacity_blends_with_the_background() {
+        let css = "div { display: block; height: 10px; background: #ff0000; opacity: 0.5; }";
+        let canvas = render("<div></div>", css, 10.0, 10.0);
+        // アルファ値は128/255に丸められる
+        let expected = Color {
+            r: 255,
+            g: 127,
+            b: 127,
+            a: 255,
+        };
+        assert_eq!(pixel(&canvas, 5, 5), expected);
+    }
+
+    #[test]
+    fn higher_z_index_paints_over_a_later_sibling() {
+        // 二つ目の箱は負のマージンで一つ目に重なる
+        let css = "div { display: block; height: 20px; position: relative; } \
+                   .over { z-index: 1; background: #ff0000; } \
+                   .under { z-index: 0; margin-top: -10px; background: #0000ff; }";
+        let html = "<div><div class='over'></div><div class='under'></div></div>";
+        let canvas = render(html, css, 10.0, 40.0);
+        assert_eq!(pixel(&canvas, 5, 15), RED);
+        let blue = Color {
+            r: 0,
+            g: 0,
+            b: 255,
+            a: 255,
+        };
+        assert_eq!(pixel(&canvas, 5, 25), blue);
+    }
+
+    #[test]
+    fn underline_is_drawn_at_the_baseline() {
+        let css = "p { display: block; text-decoration: underline; color: #ff0000; }";
+        let canvas = render("<p>abc</p>", css, 40.0, 20.0);
+        // 16pxの文字のベースラインは上から12.8px
+        let row: Vec<Color> = (0..40).map(|x| pixel(&canvas, x, 12)).collect();
+        assert!(row[..24].iter().all(|&color| color == RED));
+        assert!(row[24..].iter().all(|&color| color == WHITE));
+        assert_eq!(pixel(&canvas, 5, 5), WHITE);
+    }
+
+    #[test]
+    fn border_needs_a_style_to_be_painted() {
+        let css = "div { display: block; height: 10px; border-width: 5px; border-color: #ff0000; }";
+        let canvas = render("<div></div>", css, 40.0, 30.0);
+        assert_eq!(pixel(&canvas, 2, 2), WHITE);
+
+        let solid = format!("{} div {{ border-style: solid; }}", css);
+        let canvas = render("<div></div>", &solid, 40.0, 30.0);
+        assert_eq!(pixel(&canvas, 2, 2), RED);
+        assert_eq!(pixel(&canvas, 37, 17), RED);
+        assert_eq!(pixel(&canvas, 20, 10), WHITE);
+    }
+
+    #[test]
+    fn each_side_uses_its_own_border_color() {
+        let css = "div { display: block; height: 10px; border-width: 5px; border-style: solid; \
+                   border-color: #000000; border-top-color: #ff0000; border-bottom-color: #0000ff; }";
+        let canvas = render("<div></div>", css, 40.0, 30.0);
+        assert_eq!(pixel(&canvas, 20, 2), RED);
+        assert_eq!(pixel(&canvas, 20, 17), Color::from_hex("#0000ff").unwrap());
+        assert_eq!(pixel(&canvas, 2, 10), Color::from_hex("#000000").unwrap());
+    }
+
+    #[test]
+    fn background_image_is_tiled_over_the_content_box() {
+        let hex = |hex| Color::from_hex(hex).unwrap();
+        let image = Image {
+            width: 2,
+            height: 2,
+            pixels: vec![RED, hex("#0000ff"), hex("#008000"), hex("#000000")],
+        };
+        let css = "div { display: block; width: 4px; height: 4px; padding: 1px; \
+                   background-image: url(tile.png); }";
+        let mut viewport: Viewport = Default::default();
+        viewport.dimensions.content.width = 10.0;
+        viewport.dimensions.content.height = 10.0;
+        let canvas = with_layout_in("<div></div>", css, viewport, |root| {
+            paint_with_images(root, viewport.dimensions.content, 1.0, |url| {
+                assert_eq!(url, "tile.png");
+                Some(image.clone())
+            })
+        });
+        assert_eq!(pixel(&canvas, 0, 0), WHITE);
+        assert_eq!(pixel(&canvas, 1, 1), RED);
+        assert_eq!(pixel(&canvas, 2, 1), hex("#0000ff"));
+        assert_eq!(pixel(&canvas, 1, 2), hex("#008000"));
+        assert_eq!(pixel(&canvas, 4, 4), hex("#000000"));
+        assert_eq!(pixel(&canvas, 5, 1), WHITE);
+    }
+
+    #[test]
+    fn scale_multiplies_device_pixels() {
+        let css = "div { display: block; width: 50px; height: 50px; background-color: #ff0000; }";
+        let mut viewport: Viewport = Default::default();
+        viewport.dimensions.content.width = 50.0;
+        viewport.dimensions.content.height = 50.0;
+        let canvas = with_layout_in("<div></div>", css, viewport, |root| {
+            paint(root, viewport.dimensions.content, 2.0)
+        });
+        assert_eq!((canvas.width, canvas.height), (100, 100));
+        assert!(canvas.pixels.iter().all(|&p| p == RED));
+    }
+
+    #[test]
+    fn outline_paints_outside_the_border_box() {
+        let css = "div { display: block; } \
+                   .box { width: 10px; height: 10px; margin: 10px; \
+                          outline-style: solid; outline-width: 2px; outline-color: #ff0000; }";
+        let canvas = render("<div><div class='box'></div></div>", css, 40.0, 40.0);
+        // ボーダーボックスは(10,10)-(20,20)
+        assert_eq!(pixel(&canvas, 9, 15), RED);
+        assert_eq!(pixel(&canvas, 20, 15), RED);
+        assert_eq!(pixel(&canvas, 15, 8), RED);
+        assert_eq!(pixel(&canvas, 15, 21), RED);
+        assert_eq!(pixel(&canvas, 15, 15), WHITE);
+        assert_eq!(pixel(&canvas, 7, 15), WHITE);
+        // レイアウトには影響しない
+        with_layout_in(
+            "<div><div class='box'></div></div>",
+            css,
+            Viewport::default(),
+            |root| {
+                assert_eq!(root.dimensions.content.height, 30.0);
+            },
+        );
+    }
+
+    #[test]
+    fn current_color_background_uses_the_text_color() {
+        let css = "div { display: block; height: 10px; \
+                   background-color: currentColor; color: #008000; }";
+        let canvas = render("<div></div>", css, 10.0, 10.0);
+        assert_eq!(pixel(&canvas, 5, 5), Color::from_named("green").unwrap());
+    }
+}
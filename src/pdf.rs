@@ -41,6 +41,8 @@ fn render_item<W: Write>(item: &DisplayCommand, output: &mut W) -> io::Result<()
                 rect.height
             )
         }
+        // TODO: Embed images as XObjects.
+        DisplayCommand::Image(..) => Ok(()),
     }
 }
 
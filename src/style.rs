@@ -1,7 +1,13 @@
 use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::ptr;
+use std::slice;
 
 use crate::{
-    css::{Rule, Selector, SimpleSelector, Specificity, Stylesheet, Value},
+    css::{
+        self, Color, PseudoClass, PseudoElement, Rule, RuleIndex, Selector, SimpleSelector,
+        Specificity, Stylesheet, Unit, Value,
+    },
     dom::{ElementData, Node, NodeType},
 };
 
@@ -9,25 +15,100 @@ type PropertyMap = HashMap<String, Value>;
 
 pub enum Display {
     Inline,
+    InlineBlock,
     Block,
+    Flex,
+    Table,
+    TableRow,
+    TableCell,
     None,
 }
 
+pub enum Visibility {
+    Visible,
+    Hidden,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FontWeight {
+    Normal,
+    Bold,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FontStyle {
+    Normal,
+    Italic,
+}
+
+// ::before/::afterで生成されたテキストはDOMに存在しない
+#[derive(Debug)]
+enum StyledContent<'a> {
+    Node(&'a Node),
+    Generated(String),
+}
+
 #[derive(Debug)]
 pub struct StyledNode<'a> {
-    node: &'a Node,
+    node: StyledContent<'a>,
     specified_values: PropertyMap,
     pub children: Vec<StyledNode<'a>>,
 }
 
 impl StyledNode<'_> {
-    pub fn value(&self, name: &str) -> Option<Value> {
-        self.specified_values.get(name).map(|v| v.clone())
+    pub fn value(&self, name: &str) -> Option<&Value> {
+        self.specified_values.get(name)
+    }
+
+    pub fn value_cloned(&self, name: &str) -> Option<Value> {
+        self.value(name).cloned()
+    }
+
+    /// Return every property resolved for this node by the cascade and inheritance.
+    pub fn specified_values(&self) -> &HashMap<String, Value> {
+        &self.specified_values
+    }
+    pub fn element(&self) -> Option<&ElementData> {
+        match self.node {
+            StyledContent::Node(node) => match node.node_type {
+                NodeType::Element(ref elem) => Some(elem),
+                NodeType::Text(_) | NodeType::Comment(_) => None,
+            },
+            StyledContent::Generated(_) => None,
+        }
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        match self.node {
+            StyledContent::Node(node) => match node.node_type {
+                NodeType::Text(ref text) => Some(text),
+                NodeType::Element(_) | NodeType::Comment(_) => None,
+            },
+            StyledContent::Generated(ref text) => Some(text),
+        }
+    }
+
+    pub fn tag_name(&self) -> Option<&str> {
+        self.element().map(|elem| &*elem.tag_name)
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.text().is_some()
     }
+
     pub fn display(&self) -> Display {
+        // テキストは常にインラインとして扱う
+        if self.is_text() {
+            return Display::Inline;
+        }
         match self.value("display") {
-            Some(Value::Keyword(s)) => match &*s {
+            Some(Value::Keyword(s)) => match &**s {
                 "block" => Display::Block,
+                "inline-block" => Display::InlineBlock,
+                "flex" => Display::Flex,
+                "table" => Display::Table,
+                "table-row" => Display::TableRow,
+                "table-cell" => Display::TableCell,
                 "none" => Display::None,
                 _ => Display::Inline,
             },
@@ -35,9 +116,97 @@ impl StyledNode<'_> {
         }
     }
 
+    /// Return `display` after CSS's blockification: absolutely positioned and floated
+    /// elements are block-level even if their `display` is inline.
+    pub fn computed_display(&self) -> Display {
+        let display = self.display();
+        if self.is_text() || !matches!(display, Display::Inline | Display::InlineBlock) {
+            return display;
+        }
+        let positioned = matches!(
+            self.value("position"),
+            Some(Value::Keyword(position)) if position == "absolute" || position == "fixed"
+        );
+        let floated = matches!(
+            self.value("float"),
+            Some(Value::Keyword(float)) if float != "none"
+        );
+        if positioned || floated {
+            Display::Block
+        } else {
+            display
+        }
+    }
+
+    /// Whether the node generates a block-level box, going by `computed_display`.
+    pub fn is_block_level(&self) -> bool {
+        matches!(
+            self.computed_display(),
+            Display::Block
+                | Display::Flex
+                | Display::Table
+                | Display::TableRow
+                | Display::TableCell
+        )
+    }
+
+    pub fn is_whitespace_text(&self) -> bool {
+        self.text()
+            .is_some_and(|text| text.chars().all(char::is_whitespace))
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        match self.value("visibility") {
+            Some(Value::Keyword(s)) => match &**s {
+                "hidden" | "collapse" => Visibility::Hidden,
+                _ => Visibility::Visible,
+            },
+            _ => Visibility::Visible,
+        }
+    }
+
+    pub fn font_weight(&self) -> FontWeight {
+        match self.value("font-weight") {
+            Some(Value::Keyword(s)) => match &**s {
+                "bold" | "bolder" => FontWeight::Bold,
+                _ => FontWeight::Normal,
+            },
+            // 600以上は太字として扱う
+            Some(&Value::Number(weight)) if weight >= 600.0 => FontWeight::Bold,
+            _ => FontWeight::Normal,
+        }
+    }
+
+    pub fn font_style(&self) -> FontStyle {
+        match self.value("font-style") {
+            Some(Value::Keyword(s)) => match &**s {
+                "italic" | "oblique" => FontStyle::Italic,
+                _ => FontStyle::Normal,
+            },
+            _ => FontStyle::Normal,
+        }
+    }
+
+    /// Return whether this node is an element matching `selector`. Dynamic
+    /// pseudo-classes such as `:hover` never match here, and neither does `:root`,
+    /// since the node doesn't know whether it is the root.
+    pub fn matches_selector(&self, selector: &Selector) -> bool {
+        let cascade = Cascade {
+            indexes: &[],
+            state: &ElementState::default(),
+            root: None,
+        };
+        match self.element() {
+            Some(elem) => matches(elem, selector, None, &cascade),
+            None => false,
+        }
+    }
+
     pub fn lookup(&self, name: &str, fallback_name: &str, default: &Value) -> Value {
         self.value(name)
-            .unwrap_or_else(|| self.value(fallback_name).unwrap_or_else(|| default.clone()))
+            .or_else(|| self.value(fallback_name))
+            .unwrap_or(default)
+            .clone()
     }
 }
 
@@ -48,31 +217,313 @@ pub fn style_tree<'a>(
     stylesheet: &'a Stylesheet,
     parent_style: Option<&PropertyMap>,
 ) -> StyledNode<'a> {
-    let current_style = match &root.node_type {
-        NodeType::Element(ref elem) => specified_values(elem, stylesheet, parent_style),
-        NodeType::Text(_) => parent_style.cloned().unwrap_or_default(),
+    style_tree_multi(root, &[stylesheet], parent_style)
+}
+
+/// Like `style_tree`, but cascades several stylesheets. When two rules have the same
+/// specificity, the one from the later stylesheet wins.
+pub fn style_tree_multi<'a>(
+    root: &'a Node,
+    stylesheets: &[&'a Stylesheet],
+    parent_style: Option<&PropertyMap>,
+) -> StyledNode<'a> {
+    style_tree_with_state(root, stylesheets, parent_style, &ElementState::default())
+}
+
+/// Like `style_tree_multi`, but matches dynamic pseudo-classes such as `:hover`
+/// against `state`. Call it again to restyle after the state changes.
+pub fn style_tree_with_state<'a>(
+    root: &'a Node,
+    stylesheets: &[&'a Stylesheet],
+    parent_style: Option<&PropertyMap>,
+    state: &ElementState,
+) -> StyledNode<'a> {
+    let indexes: Vec<RuleIndex> = stylesheets
+        .iter()
+        .map(|stylesheet| stylesheet.build_index())
+        .collect();
+    let cascade = Cascade {
+        indexes: &indexes,
+        state: state,
+        root: root_element(root),
     };
+    style_node(root, &cascade, parent_style)
+}
 
-    let children_styles = root
-        .children
+/// Recompute the styles that may depend on rules with the `changed` selectors, e.g.
+/// after those rules were edited, leaving every other node's values untouched. An
+/// element matching a changed selector is restyled with its whole subtree, since its
+/// descendants may inherit from it. `parent_style` must be the one `styled` was
+/// originally computed with.
+pub fn restyle<'a>(
+    styled: &mut StyledNode<'a>,
+    stylesheets: &[&'a Stylesheet],
+    parent_style: Option<&PropertyMap>,
+    state: &ElementState,
+    changed: &[&Selector],
+) {
+    let indexes: Vec<RuleIndex> = stylesheets
         .iter()
-        .map(|child| style_tree(child, stylesheet, Some(&current_style)))
+        .map(|stylesheet| stylesheet.build_index())
         .collect();
+    let root = match styled.node {
+        StyledContent::Node(node) => root_element(node),
+        StyledContent::Generated(_) => None,
+    };
+    let cascade = Cascade {
+        indexes: &indexes,
+        state: state,
+        root: root,
+    };
+    restyle_node(styled, &cascade, parent_style, changed);
+}
 
-    StyledNode {
-        node: root,
+fn root_element(node: &Node) -> Option<&ElementData> {
+    match node.node_type {
+        NodeType::Element(ref elem) => Some(elem),
+        NodeType::Text(_) | NodeType::Comment(_) => None,
+    }
+}
+
+// 計算し直したらtrueを返す
+fn restyle_node<'a>(
+    styled: &mut StyledNode<'a>,
+    cascade: &Cascade<'a, '_>,
+    parent_style: Option<&PropertyMap>,
+    changed: &[&Selector],
+) -> bool {
+    // 擬似要素のテキストは、その要素と一緒に計算し直される
+    let node = match styled.node {
+        StyledContent::Node(node) => node,
+        StyledContent::Generated(_) => return false,
+    };
+    let affected = match node.node_type {
+        NodeType::Element(ref elem) => changed
+            .iter()
+            .any(|selector| may_match(elem, selector, cascade)),
+        NodeType::Text(_) | NodeType::Comment(_) => false,
+    };
+    if affected {
+        *styled = style_node(node, cascade, parent_style);
+        return true;
+    }
+    let mut restyled = false;
+    for child in &mut styled.children {
+        restyled |= restyle_node(child, cascade, Some(&styled.specified_values), changed);
+    }
+    // 子要素のdisplayが変わると、ブロックに隣接する空白も変わるので取り除き直す
+    if restyled && !is_template(node) {
+        refresh_children(styled, node, cascade);
+    }
+    false
+}
+
+// 取り除いた空白のテキストを戻してから、擬似要素と空白の処理をやり直す
+fn refresh_children<'a>(styled: &mut StyledNode<'a>, node: &'a Node, cascade: &Cascade<'a, '_>) {
+    let mut old_children = mem::take(&mut styled.children)
+        .into_iter()
+        .filter(|child| matches!(child.node, StyledContent::Node(_)))
+        .peekable();
+    let mut children = Vec::new();
+    for child in &node.children {
+        if matches!(child.node_type, NodeType::Comment(_)) {
+            continue;
+        }
+        match old_children.peek() {
+            Some(&StyledNode {
+                node: StyledContent::Node(old),
+                ..
+            }) if ptr::eq(old, child) => children.extend(old_children.next()),
+            // 取り除かれていたのは空白だけのテキストなので、子ノードはない
+            _ => children.push(open_node(child, cascade, Some(&styled.specified_values)).0),
+        }
+    }
+    styled.children = children;
+    finish_node(styled, cascade);
+}
+
+// 擬似要素のセレクターは、その擬似要素を持つ要素に一致するものとする
+fn may_match(elem: &ElementData, selector: &Selector, cascade: &Cascade) -> bool {
+    let Selector::Simple(ref simple) = *selector;
+    matches_simple_selector(elem, simple, simple.pseudo_element, cascade)
+}
+
+/// Dynamic element state that pseudo-classes match against. Elements are identified
+/// by their address, so the state must be built from the same DOM tree being styled.
+#[derive(Debug, Default)]
+pub struct ElementState {
+    hovered: HashSet<*const ElementData>,
+}
+
+impl ElementState {
+    pub fn set_hovered(&mut self, node: &Node, hovered: bool) {
+        if let NodeType::Element(ref elem) = node.node_type {
+            if hovered {
+                self.hovered.insert(elem);
+            } else {
+                self.hovered.remove(&(elem as *const ElementData));
+            }
+        }
+    }
+
+    fn is_hovered(&self, elem: &ElementData) -> bool {
+        self.hovered.contains(&(elem as *const ElementData))
+    }
+}
+
+// スタイルの計算中に共有する情報
+struct Cascade<'a, 'b> {
+    indexes: &'b [RuleIndex<'a>],
+    state: &'b ElementState,
+    // :rootに一致する文書のルート要素
+    root: Option<&'b ElementData>,
+}
+
+fn style_node<'a>(
+    root: &'a Node,
+    cascade: &Cascade<'a, '_>,
+    parent_style: Option<&PropertyMap>,
+) -> StyledNode<'a> {
+    // 深い文書でスタックを使い果たさないよう、再帰せずに明示的なスタックで辿る
+    let mut stack = vec![open_node(root, cascade, parent_style)];
+    loop {
+        let (parent, pending) = stack.last_mut().unwrap();
+        match pending.next() {
+            // コメントはスタイルもレイアウトも持たない
+            Some(child) if matches!(child.node_type, NodeType::Comment(_)) => {}
+            Some(child) => {
+                let child = open_node(child, cascade, Some(&parent.specified_values));
+                stack.push(child);
+            }
+            None => {
+                let (mut finished, _) = stack.pop().unwrap();
+                finish_node(&mut finished, cascade);
+                match stack.last_mut() {
+                    Some((parent, _)) => parent.children.push(finished),
+                    None => return finished,
+                }
+            }
+        }
+    }
+}
+
+// ノードのスタイルを計算し、これからスタイルを計算する子ノードと一緒に返す
+fn open_node<'a>(
+    node: &'a Node,
+    cascade: &Cascade<'a, '_>,
+    parent_style: Option<&PropertyMap>,
+) -> (StyledNode<'a>, slice::Iter<'a, Node>) {
+    let mut current_style = match &node.node_type {
+        NodeType::Element(ref elem) => specified_values(elem, cascade, parent_style, None),
+        NodeType::Text(_) | NodeType::Comment(_) => parent_style.cloned().unwrap_or_default(),
+    };
+
+    // templateの中身はDOMには残すが描画しないので、スタイルを計算しない
+    let children = if is_template(node) {
+        current_style.insert("display".to_string(), Value::keyword("none"));
+        [].iter()
+    } else {
+        node.children.iter()
+    };
+
+    let styled = StyledNode {
+        node: StyledContent::Node(node),
         specified_values: current_style,
-        children: children_styles,
+        children: Vec::new(),
+    };
+    (styled, children)
+}
+
+// 子ノードのスタイルが揃った後に、擬似要素を加えて表示されない空白を取り除く
+fn finish_node(styled: &mut StyledNode, cascade: &Cascade) {
+    let node = match styled.node {
+        StyledContent::Node(node) if !is_template(node) => node,
+        _ => return,
+    };
+    if let NodeType::Element(ref elem) = node.node_type {
+        let style = &styled.specified_values;
+        let before = generated_content(elem, cascade, style, PseudoElement::Before);
+        let after = generated_content(elem, cascade, style, PseudoElement::After);
+        if let Some(before) = before {
+            styled.children.insert(0, before);
+        }
+        styled.children.extend(after);
     }
+    remove_whitespace_next_to_blocks(&mut styled.children);
+}
+
+// ブロックレベルの兄弟に隣接する空白だけのテキストは表示されないので、スタイルツリーから取り除く
+fn remove_whitespace_next_to_blocks(children: &mut Vec<StyledNode>) {
+    let keep: Vec<bool> = (0..children.len())
+        .map(|i| !is_whitespace_next_to_block(children, i))
+        .collect();
+    let mut keep = keep.into_iter();
+    children.retain(|_| keep.next().unwrap());
+}
+
+/// Whether `children[i]` is whitespace-only text next to a block-level sibling,
+/// which renders nothing.
+pub fn is_whitespace_next_to_block(children: &[StyledNode], i: usize) -> bool {
+    let next_to_block = (i > 0 && children[i - 1].is_block_level())
+        || children.get(i + 1).is_some_and(StyledNode::is_block_level);
+    next_to_block && children[i].is_whitespace_text()
 }
 
-fn matches(elem: &ElementData, selector: &Selector) -> bool {
+fn is_template(node: &Node) -> bool {
+    matches!(node.node_type, NodeType::Element(ref elem) if elem.tag_name == "template")
+}
+
+// 擬似要素のcontentに文字列が指定されていれば、その文字列のテキストノードを生成する
+fn generated_content<'a>(
+    elem: &ElementData,
+    cascade: &Cascade,
+    element_style: &PropertyMap,
+    pseudo_element: PseudoElement,
+) -> Option<StyledNode<'a>> {
+    let values = specified_values(elem, cascade, Some(element_style), Some(pseudo_element));
+    let content = match values.get("content") {
+        Some(Value::Str(content)) => content.clone(),
+        _ => return None,
+    };
+    if matches!(values.get("display"), Some(Value::Keyword(display)) if display == "none") {
+        return None;
+    }
+    Some(StyledNode {
+        node: StyledContent::Generated(content),
+        specified_values: values,
+        children: Vec::new(),
+    })
+}
+
+fn matches(
+    elem: &ElementData,
+    selector: &Selector,
+    pseudo_element: Option<PseudoElement>,
+    cascade: &Cascade,
+) -> bool {
     match *selector {
-        Selector::Simple(ref simple_selector) => matches_simple_selector(elem, simple_selector),
+        Selector::Simple(ref simple_selector) => {
+            matches_simple_selector(elem, simple_selector, pseudo_element, cascade)
+        }
     }
 }
 
-fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> bool {
+fn matches_simple_selector(
+    elem: &ElementData,
+    selector: &SimpleSelector,
+    pseudo_element: Option<PseudoElement>,
+    cascade: &Cascade,
+) -> bool {
+    if selector.pseudo_element != pseudo_element {
+        return false;
+    }
+    if selector
+        .pseudo_classes
+        .iter()
+        .any(|pseudo_class| !matches_pseudo_class(elem, pseudo_class, cascade))
+    {
+        return false;
+    }
     if selector.tag_name.iter().any(|name| elem.tag_name != *name) {
         return false;
     }
@@ -92,35 +543,94 @@ fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> boo
     true
 }
 
-fn match_rule<'a>(elem: &ElementData, rule: &'a Rule) -> Option<MatchedRule<'a>> {
+fn matches_pseudo_class(elem: &ElementData, pseudo_class: &PseudoClass, cascade: &Cascade) -> bool {
+    match *pseudo_class {
+        PseudoClass::Hover => cascade.state.is_hovered(elem),
+        PseudoClass::Root => cascade.root.is_some_and(|root| ptr::eq(root, elem)),
+        PseudoClass::Not(ref inner) => !matches_simple_selector(elem, inner, None, cascade),
+    }
+}
+
+fn match_rule<'a>(
+    elem: &ElementData,
+    rule: &'a Rule,
+    pseudo_element: Option<PseudoElement>,
+    cascade: &Cascade,
+) -> Option<MatchedRule<'a>> {
     rule.selectors
         .iter()
-        .find(|selector| matches(elem, *selector))
+        .find(|selector| matches(elem, *selector, pseudo_element, cascade))
         .map(|selector| (selector.specificity(), rule))
 }
 
-fn matching_rules<'a>(elem: &ElementData, stylesheet: &'a Stylesheet) -> Vec<MatchedRule<'a>> {
-    stylesheet
-        .rules
+fn matching_rules<'a>(
+    elem: &ElementData,
+    cascade: &Cascade<'a, '_>,
+    pseudo_element: Option<PseudoElement>,
+) -> Vec<MatchedRule<'a>> {
+    let classes: Vec<&str> = elem.classes().into_iter().collect();
+    cascade
+        .indexes
         .iter()
-        .filter_map(|rule| match_rule(elem, rule))
+        .flat_map(|index| index.candidates(&elem.tag_name, elem.id().map(|id| &**id), &classes))
+        .filter_map(|rule| match_rule(elem, rule, pseudo_element, cascade))
         .collect()
 }
 
 fn specified_values(
     elem: &ElementData,
-    stylesheet: &Stylesheet,
+    cascade: &Cascade,
     parent_style: Option<&PropertyMap>,
+    pseudo_element: Option<PseudoElement>,
 ) -> PropertyMap {
-    let mut values = HashMap::new();
-    let mut rules = matching_rules(elem, stylesheet);
+    // Presentational attributes have the lowest priority, so author rules override them.
+    let mut values = match pseudo_element {
+        Some(_) => HashMap::new(),
+        None => presentational_hints(elem),
+    };
+    let mut rules = matching_rules(elem, cascade, pseudo_element);
+
+    // hidden属性は詳細度が(0, 1, 0)の`[hidden] { display: none }`が作者のスタイルシートより
+    // 前にあるものとして扱う。詳細度がそれ以上の作者のルールとstyle属性だけがdisplayを上書きできる
+    let hidden_specificity = Specificity {
+        ids: 0,
+        classes: 1,
+        tags: 0,
+    };
+    let mut hidden = pseudo_element.is_none() && elem.attr("hidden").is_some();
 
     rules.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
-    for (_, rule) in rules {
+    for (specificity, rule) in rules {
+        if hidden && specificity >= hidden_specificity {
+            values.insert("display".to_string(), Value::keyword("none"));
+            hidden = false;
+        }
         for declaration in &rule.declarations {
-            values.insert(declaration.name.clone(), declaration.value.clone());
+            let name = physical_property(&declaration.name);
+            values.insert(name.to_string(), declaration.value.clone());
         }
     }
+    if hidden {
+        values.insert("display".to_string(), Value::keyword("none"));
+    }
+    // style属性の宣言はどのルールよりも優先する
+    if let (None, Some(style)) = (pseudo_element, elem.attr("style")) {
+        for declaration in css::parse_declarations(style) {
+            let name = physical_property(&declaration.name);
+            values.insert(name.to_string(), declaration.value);
+        }
+    }
+
+    // カスタムプロパティはすべて継承される
+    if let Some(parent_style) = parent_style {
+        for (name, value) in parent_style {
+            if name.starts_with("--") && !values.contains_key(name) {
+                values.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    resolve_variables(&mut values);
+    resolve_border_widths(&mut values);
 
     let inheritable_props = inheritable_properties();
     if let Some(parent_style) = parent_style {
@@ -133,6 +643,177 @@ fn specified_values(
         }
     }
 
+    // カスケードでも継承でも決まらなかったプロパティは初期値にする
+    for &prop in INITIAL_VALUE_PROPERTIES.iter() {
+        if !values.contains_key(prop) {
+            if let Some(value) = initial_value(prop) {
+                values.insert(prop.to_string(), value);
+            }
+        }
+    }
+
+    values
+}
+
+// borderとoutlineの太さのキーワードを長さにする
+fn resolve_border_widths(values: &mut PropertyMap) {
+    for name in BORDER_WIDTH_PROPERTIES {
+        if let Some(Value::Keyword(keyword)) = values.get(name) {
+            let px = match &**keyword {
+                "thin" => 1.0,
+                "medium" => 3.0,
+                "thick" => 5.0,
+                _ => continue,
+            };
+            values.insert(name.to_string(), Value::Length(px, Unit::Px));
+        }
+    }
+}
+
+const BORDER_WIDTH_PROPERTIES: [&str; 6] = [
+    "border-width",
+    "border-top-width",
+    "border-right-width",
+    "border-bottom-width",
+    "border-left-width",
+    "outline-width",
+];
+
+// var()をカスタムプロパティの値に置き換える。値が見つからずフォールバックもなければ、
+// 宣言がなかったものとして継承値か初期値を使う。参照が循環しているカスタムプロパティは、
+// フォールバックがあっても無効になる
+fn resolve_variables(values: &mut PropertyMap) {
+    // 置き換えた値が他のプロパティの置き換えに影響しないよう、元の値を参照する
+    let specified = values.clone();
+    let cyclic = cyclic_properties(&specified);
+    for (name, value) in &specified {
+        if !matches!(value, Value::Var(..)) {
+            continue;
+        }
+        let substituted = if cyclic.contains(name) {
+            None
+        } else {
+            substitute_var(value, &specified, &cyclic)
+        };
+        match substituted {
+            // 複数の値は、カスタムプロパティ以外では一つの値として使えない
+            Some(Value::List(_)) if !name.starts_with("--") => values.remove(name),
+            Some(value) => values.insert(name.clone(), value),
+            None => values.remove(name),
+        };
+    }
+}
+
+fn substitute_var(value: &Value, values: &PropertyMap, cyclic: &HashSet<String>) -> Option<Value> {
+    let (name, fallback) = match *value {
+        Value::Var(ref name, ref fallback) => (name, fallback),
+        _ => return Some(value.clone()),
+    };
+    let substituted = match values.get(name) {
+        Some(value) if !cyclic.contains(name) => substitute_var(value, values, cyclic),
+        _ => None,
+    };
+    substituted.or_else(|| {
+        fallback
+            .as_ref()
+            .and_then(|fallback| substitute_var(fallback, values, cyclic))
+    })
+}
+
+// 自身の値からvar()を辿って自身に戻ってくるカスタムプロパティ
+fn cyclic_properties(values: &PropertyMap) -> HashSet<String> {
+    values
+        .iter()
+        .filter(|(name, value)| {
+            name.starts_with("--") && references(value, name, values, &mut HashSet::new())
+        })
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+// `value`がフォールバックも含めて、直接または間接に`target`を参照しているかどうか
+fn references(
+    value: &Value,
+    target: &str,
+    values: &PropertyMap,
+    visited: &mut HashSet<String>,
+) -> bool {
+    let (name, fallback) = match *value {
+        Value::Var(ref name, ref fallback) => (name, fallback),
+        _ => return false,
+    };
+    if name == target {
+        return true;
+    }
+    let through_name = visited.insert(name.clone())
+        && values
+            .get(name)
+            .is_some_and(|value| references(value, target, values, visited));
+    through_name
+        || fallback
+            .as_ref()
+            .is_some_and(|fallback| references(fallback, target, values, visited))
+}
+
+// 論理プロパティを物理プロパティに置き換える。横書きしかないので、インライン方向は幅になる
+fn physical_property(name: &str) -> &str {
+    match name {
+        "inline-size" => "width",
+        "block-size" => "height",
+        "min-inline-size" => "min-width",
+        "max-inline-size" => "max-width",
+        "min-block-size" => "min-height",
+        "max-block-size" => "max-height",
+        _ => name,
+    }
+}
+
+const INITIAL_VALUE_PROPERTIES: [&str; 5] = ["display", "color", "font-size", "margin", "padding"];
+
+/// Return the CSS initial value of `property`, or `None` if it has no entry in the table.
+pub fn initial_value(property: &str) -> Option<Value> {
+    match property {
+        "display" => Some(Value::keyword("inline")),
+        "color" => Some(Value::ColorValue(Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 255,
+        })),
+        "font-size" => Some(Value::keyword("medium")),
+        "margin" | "padding" => Some(Value::Length(0.0, Unit::Px)),
+        _ => None,
+    }
+}
+
+/// Map legacy presentational attributes (`width`, `height`, `bgcolor`) to properties.
+fn presentational_hints(elem: &ElementData) -> PropertyMap {
+    let mut values = HashMap::new();
+
+    let sized = matches!(
+        &*elem.tag_name,
+        "img" | "table" | "td" | "th" | "iframe" | "video" | "canvas"
+    );
+    if sized {
+        for name in ["width", "height"] {
+            let length = elem
+                .attr(name)
+                .and_then(|v| v.trim().trim_end_matches("px").parse().ok());
+            if let Some(length) = length {
+                values.insert(name.to_string(), Value::Length(length, Unit::Px));
+            }
+        }
+    }
+
+    if matches!(&*elem.tag_name, "body" | "table" | "tr" | "td" | "th") {
+        let bgcolor = elem.attr("bgcolor");
+        let color =
+            bgcolor.and_then(|value| Color::from_hex(value).or_else(|| Color::from_named(value)));
+        if let Some(color) = color {
+            values.insert("background-color".to_string(), Value::ColorValue(color));
+        }
+    }
+
     values
 }
 
@@ -140,5 +821,571 @@ fn inheritable_properties() -> HashSet<&'static str> {
     let mut props = HashSet::new();
     props.insert("color");
     props.insert("font-family");
+    props.insert("font-weight");
+    props.insert("font-style");
+    props.insert("letter-spacing");
+    props.insert("visibility");
+    props.insert("overflow-wrap");
+    props.insert("word-break");
+    props.insert("white-space");
+    props.insert("line-height");
+    props.insert("direction");
     props
 }
+
+/// The role of a node in the accessibility tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    Document,
+    Heading(u8),
+    Link,
+    Button,
+    Image,
+    StaticText,
+}
+
+/// A node of the accessibility tree built by `accessibility_tree`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AxNode {
+    pub role: Role,
+    pub name: String,
+    pub children: Vec<AxNode>,
+}
+
+/// Build a simplified accessibility tree. Elements without a role of their own are
+/// left out and their children take their place; `display: none` and
+/// `aria-hidden="true"` subtrees are skipped.
+pub fn accessibility_tree(root: &StyledNode) -> AxNode {
+    let mut children = Vec::new();
+    collect_ax_nodes(root, &mut children);
+    AxNode {
+        role: Role::Document,
+        name: String::new(),
+        children: children,
+    }
+}
+
+fn collect_ax_nodes(node: &StyledNode, output: &mut Vec<AxNode>) {
+    if matches!(node.display(), Display::None) {
+        return;
+    }
+    // aria-hiddenは表示には影響せず、アクセシビリティツリーからだけ取り除く
+    if node.element().and_then(|elem| elem.attr("aria-hidden")) == Some("true") {
+        return;
+    }
+    if let Some(text) = node.text() {
+        let name = collapse_whitespace(text);
+        if !name.is_empty() {
+            output.push(AxNode {
+                role: Role::StaticText,
+                name: name,
+                children: vec![],
+            });
+        }
+        return;
+    }
+
+    let role = node.tag_name().and_then(|tag_name| match tag_name {
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            Some(Role::Heading(tag_name.as_bytes()[1] - b'0'))
+        }
+        "a" => Some(Role::Link),
+        "button" => Some(Role::Button),
+        "img" => Some(Role::Image),
+        _ => None,
+    });
+    match role {
+        // 役割を持つ要素の名前は、imgならalt属性、それ以外は子孫のテキストから作る
+        Some(role) => {
+            let name = match role {
+                Role::Image => node
+                    .element()
+                    .and_then(|elem| elem.attr("alt"))
+                    .unwrap_or("")
+                    .to_string(),
+                _ => {
+                    let mut text = String::new();
+                    collect_visible_text(node, &mut text);
+                    collapse_whitespace(&text)
+                }
+            };
+            output.push(AxNode {
+                role: role,
+                name: name,
+                children: vec![],
+            });
+        }
+        None => {
+            for child in &node.children {
+                collect_ax_nodes(child, output);
+            }
+        }
+    }
+}
+
+fn collect_visible_text(node: &StyledNode, output: &mut String) {
+    if matches!(node.display(), Display::None) {
+        return;
+    }
+    match node.text() {
+        Some(text) => {
+            output.push_str(text);
+            output.push(' ');
+        }
+        None => {
+            for child in &node.children {
+                collect_visible_text(child, output);
+            }
+        }
+    }
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::with_style;
+    use crate::{css, dom, html};
+    use std::ptr;
+
+    fn color(name: &str) -> Option<Value> {
+        Color::from_hex(name)
+            .or_else(|| Color::from_named(name))
+            .map(Value::ColorValue)
+    }
+
+    #[test]
+    fn font_weight_is_inherited_and_overridden() {
+        let css = "div { font-weight: bold; font-style: italic; } em { font-weight: normal; } \
+                   b { font-weight: 700; } i { font-weight: 500; }";
+        let html = "<div><span>a</span><em>b</em><b>c</b><i>d</i></div>";
+        with_style(html, css, |root| {
+            let weights: Vec<FontWeight> = root.children.iter().map(|c| c.font_weight()).collect();
+            assert_eq!(
+                weights,
+                [
+                    FontWeight::Bold,
+                    FontWeight::Normal,
+                    FontWeight::Bold,
+                    FontWeight::Normal
+                ]
+            );
+            assert_eq!(root.children[1].font_style(), FontStyle::Italic);
+        });
+    }
+
+    fn elements(node: &Node) -> Vec<&ElementData> {
+        let mut found = Vec::new();
+        if let NodeType::Element(ref elem) = node.node_type {
+            found.push(elem);
+        }
+        for child in &node.children {
+            found.extend(elements(child));
+        }
+        found
+    }
+
+    #[test]
+    fn rule_index_matches_like_a_linear_scan() {
+        let tags = ["div", "p", "span", "*"];
+        let mut css = String::new();
+        for i in 0..100 {
+            let selector = match i % 5 {
+                0 => tags[i % 4].to_string(),
+                1 => format!(".c{}", i % 7),
+                2 => format!("#i{}", i % 3),
+                3 => format!("{}.c{}.c{}", tags[i % 3], i % 7, (i + 1) % 7),
+                _ => format!("{}#i{}.c{}, .c{}", tags[i % 4], i % 3, i % 7, (i + 2) % 7),
+            };
+            css.push_str(&format!("{} {{ margin: {}px; }}\n", selector, i));
+        }
+        let stylesheet = css::parse(css);
+        let root = html::parse(
+            "<div id='i0' class='c1 c2'><p class='c3'>a</p><span id='i1' class='c4 c5'>b</span>\
+             <p id='i2' class='c0 c6'>c</p><div class='c5'><span>d</span></div></div>"
+                .to_string(),
+        );
+        let indexes = [stylesheet.build_index()];
+        let state = ElementState::default();
+        let cascade = Cascade {
+            indexes: &indexes,
+            state: &state,
+            root: root_element(&root),
+        };
+        for elem in elements(&root) {
+            let indexed: Vec<*const Rule> = matching_rules(elem, &cascade, None)
+                .into_iter()
+                .map(|(_, rule)| rule as *const Rule)
+                .collect();
+            let linear: Vec<*const Rule> = stylesheet
+                .rules
+                .iter()
+                .filter_map(|rule| match_rule(elem, rule, None, &cascade))
+                .map(|(_, rule)| rule as *const Rule)
+                .collect();
+            assert!(!linear.is_empty());
+            assert_eq!(indexed, linear, "<{}>", elem.tag_name);
+        }
+    }
+
+    #[test]
+    fn value_borrows_the_stored_value() {
+        with_style(
+            "<p>a</p>",
+            "p { color: #ff0000; font-family: serif; }",
+            |p| {
+                // 同じ値を指す参照が返るので、呼び出しのたびに複製されていない
+                let first = p.value("font-family").unwrap();
+                let second = p.value("font-family").unwrap();
+                assert!(ptr::eq(first, second));
+                assert!(ptr::eq(first, &p.specified_values["font-family"]));
+                assert_eq!(p.value_cloned("color"), color("#ff0000"));
+                assert_eq!(p.value("color"), color("#ff0000").as_ref());
+            },
+        );
+    }
+
+    #[test]
+    fn bgcolor_is_overridden_by_author_rules() {
+        let html =
+            "<table><tr><td bgcolor=\"red\">a</td><td bgcolor=\"#00ff00\">b</td></tr></table>";
+        with_style(html, "", |table| {
+            let row = &table.children[0];
+            assert_eq!(
+                row.children[0].value_cloned("background-color"),
+                color("red")
+            );
+            assert_eq!(
+                row.children[1].value_cloned("background-color"),
+                color("lime")
+            );
+        });
+        with_style(html, "td { background-color: blue; }", |table| {
+            let row = &table.children[0];
+            assert_eq!(
+                row.children[0].value_cloned("background-color"),
+                color("blue")
+            );
+        });
+    }
+
+    #[test]
+    fn tag_name_of_elements_and_text() {
+        with_style("<div>a</div>", "", |div| {
+            assert_eq!(div.tag_name(), Some("div"));
+            assert_eq!(div.children[0].tag_name(), None);
+        });
+    }
+
+    #[test]
+    fn specified_values_are_the_union_of_matching_rules() {
+        let html = "<div class='a'>x</div>";
+        let initial: HashSet<String> = with_style(html, "", |div| {
+            div.specified_values().keys().cloned().collect()
+        });
+        let css = "div { width: 10px; color: #ff0000; } .a { height: 5px; color: #0000ff; }";
+        with_style(html, css, |div| {
+            let names: HashSet<String> = div.specified_values().keys().cloned().collect();
+            let mut expected = initial.clone();
+            expected.extend(["width", "height", "color"].map(String::from));
+            assert_eq!(names, expected);
+            assert_eq!(div.value_cloned("color"), color("#0000ff"));
+            assert_eq!(
+                div.value_cloned("width"),
+                Some(Value::Length(10.0, Unit::Px))
+            );
+        });
+    }
+
+    #[test]
+    fn later_stylesheet_wins_ties() {
+        let root = html::parse("<p>a</p>".to_string());
+        let first = css::parse("p { color: #ff0000; width: 1px; }".to_string());
+        let second = css::parse("p { color: #0000ff; }".to_string());
+        let styled = style_tree_multi(&root, &[&first, &second], None);
+        assert_eq!(styled.value_cloned("color"), color("#0000ff"));
+        assert_eq!(
+            styled.value_cloned("width"),
+            Some(Value::Length(1.0, Unit::Px))
+        );
+        let styled = style_tree_multi(&root, &[&second, &first], None);
+        assert_eq!(styled.value_cloned("color"), color("#ff0000"));
+    }
+
+    #[test]
+    fn hover_matches_only_hovered_elements() {
+        let root = html::parse("<div><a>x</a><a>y</a></div>".to_string());
+        let stylesheet = css::parse("a:hover { color: #ff0000; }".to_string());
+        let mut state = ElementState::default();
+        state.set_hovered(&root.children[0], true);
+        let styled = style_tree_with_state(&root, &[&stylesheet], None, &state);
+        assert_eq!(styled.children[0].value_cloned("color"), color("#ff0000"));
+        assert_ne!(styled.children[1].value_cloned("color"), color("#ff0000"));
+
+        state.set_hovered(&root.children[0], false);
+        let styled = style_tree_with_state(&root, &[&stylesheet], None, &state);
+        assert_ne!(styled.children[0].value_cloned("color"), color("#ff0000"));
+    }
+
+    #[test]
+    fn styled_node_matches_a_class_selector() {
+        let stylesheet = css::parse(".foo {} .bar {} :hover {}".to_string());
+        let selector = |i: usize| &stylesheet.rules[i].selectors[0];
+        with_style("<div class='foo'>x</div>", "", |div| {
+            assert!(div.matches_selector(selector(0)));
+            assert!(!div.matches_selector(selector(1)));
+            assert!(!div.matches_selector(selector(2)));
+            assert!(!div.children[0].matches_selector(selector(0)));
+        });
+    }
+
+    #[test]
+    fn not_excludes_matching_elements() {
+        let css = "div:not(.skip) { color: #ff0000; }";
+        with_style(
+            "<body><div>a</div><div class='skip'>b</div></body>",
+            css,
+            |body| {
+                assert_eq!(body.children[0].value_cloned("color"), color("#ff0000"));
+                assert_ne!(body.children[1].value_cloned("color"), color("#ff0000"));
+            },
+        );
+    }
+
+    #[test]
+    fn unstyled_element_gets_initial_values() {
+        with_style("<div>a</div>", "", |div| {
+            for name in INITIAL_VALUE_PROPERTIES {
+                assert_eq!(div.value_cloned(name), initial_value(name), "{}", name);
+            }
+            assert_eq!(div.value_cloned("display"), Some(Value::keyword("inline")));
+            assert_eq!(div.value_cloned("color"), color("#000000"));
+            assert_eq!(
+                div.value_cloned("margin"),
+                Some(Value::Length(0.0, Unit::Px))
+            );
+            assert_eq!(initial_value("no-such-property"), None);
+        });
+    }
+
+    #[test]
+    fn style_attribute_overrides_every_rule() {
+        let css = "#a { color: #ff0000; width: 1px; }";
+        with_style(
+            "<div id='a' style='color: #0000ff; height: 2px'>x</div>",
+            css,
+            |div| {
+                assert_eq!(div.value_cloned("color"), color("#0000ff"));
+                assert_eq!(
+                    div.value_cloned("width"),
+                    Some(Value::Length(1.0, Unit::Px))
+                );
+                assert_eq!(
+                    div.value_cloned("height"),
+                    Some(Value::Length(2.0, Unit::Px))
+                );
+                // 子要素にも継承される
+                assert_eq!(div.children[0].value_cloned("color"), color("#0000ff"));
+            },
+        );
+    }
+
+    #[test]
+    fn id_outweighs_later_rule_with_more_classes() {
+        let css = "div#id.a.b { color: #ff0000; } .a.b.c { color: #0000ff; }";
+        with_style("<div id='id' class='a b c'>x</div>", css, |root| {
+            assert_eq!(root.value_cloned("color"), color("#ff0000"));
+        });
+    }
+
+    #[test]
+    fn border_overrides_earlier_side_shorthands() {
+        let css = "p { border-top: 1px dotted #ff0000; border: 2px solid #000000; } \
+                   em { border: 2px solid; border-color: #ff0000; }";
+        with_style("<div><p>a</p><em>b</em></div>", css, |root| {
+            let p = &root.children[0];
+            assert_eq!(
+                p.value_cloned("border-top-width"),
+                Some(Value::Length(2.0, Unit::Px))
+            );
+            assert_eq!(
+                p.value_cloned("border-top-style"),
+                Some(Value::keyword("solid"))
+            );
+            assert_eq!(p.value_cloned("border-top-color"), color("#000000"));
+            let em = &root.children[1];
+            assert_eq!(em.value_cloned("border-left-color"), color("#ff0000"));
+        });
+    }
+
+    #[test]
+    fn accessibility_tree_lists_heading_and_link() {
+        let html = "<body><h2>Title <em>here</em></h2><p>See <a href='x'>the docs</a>.</p>\
+                    <p aria-hidden='true'><a href='y'>hidden</a></p></body>";
+        with_style(html, "", |root| {
+            let roles: Vec<(Role, String)> = accessibility_tree(root)
+                .children
+                .into_iter()
+                .map(|node| (node.role, node.name))
+                .collect();
+            assert_eq!(
+                roles,
+                [
+                    (Role::Heading(2), "Title here".to_string()),
+                    (Role::StaticText, "See".to_string()),
+                    (Role::Link, "the docs".to_string()),
+                    (Role::StaticText, ".".to_string()),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn restyle_touches_only_matching_subtrees() {
+        let root = html::parse("<div><div class='foo'><p>a</p></div><p>b</p></div>".to_string());
+        let before = css::parse("p { color: #ff0000; } .foo { margin: 1px; }".to_string());
+        let after = css::parse("p { color: #0000ff; } .foo { margin: 2px; }".to_string());
+        let mut styled = style_tree(&root, &before, None);
+        let untouched = styled.children[1].specified_values.clone();
+
+        let changed = [&after.rules[1].selectors[0]];
+        restyle(
+            &mut styled,
+            &[&after],
+            None,
+            &ElementState::default(),
+            &changed,
+        );
+        let foo = &styled.children[0];
+        assert_eq!(
+            foo.value_cloned("margin"),
+            Some(Value::Length(2.0, Unit::Px))
+        );
+        // .fooの子孫も計算し直される
+        assert_eq!(foo.children[0].value_cloned("color"), color("#0000ff"));
+        assert_eq!(styled.children[1].specified_values, untouched);
+        assert_eq!(styled.children[1].value_cloned("color"), color("#ff0000"));
+    }
+
+    fn assert_same_tree(actual: &StyledNode, expected: &StyledNode) {
+        assert_eq!(actual.tag_name(), expected.tag_name());
+        assert_eq!(actual.text(), expected.text());
+        assert_eq!(actual.specified_values, expected.specified_values);
+        assert_eq!(actual.children.len(), expected.children.len());
+        for (actual, expected) in actual.children.iter().zip(&expected.children) {
+            assert_same_tree(actual, expected);
+        }
+    }
+
+    #[test]
+    fn restyle_restores_whitespace_next_to_former_blocks() {
+        // html::parseは空白だけのテキストを捨てるので、DOMを直接組み立てる
+        let class = HashMap::from([("class".to_string(), "x".to_string())]);
+        let span = |attributes, text: &str| {
+            dom::elem(
+                "span".to_string(),
+                attributes,
+                vec![dom::text(text.to_string())],
+            )
+        };
+        let children = vec![
+            span(class, "a"),
+            dom::text(" ".to_string()),
+            span(HashMap::new(), "b"),
+        ];
+        let root = dom::elem("div".to_string(), HashMap::new(), children);
+        let block = css::parse(".x { display: block; }".to_string());
+        let inline = css::parse(".x { display: inline; }".to_string());
+
+        // ブロックでなくなった兄弟の隣の空白は戻る
+        let mut styled = style_tree(&root, &block, None);
+        assert_eq!(styled.children.len(), 2);
+        let changed = [&inline.rules[0].selectors[0]];
+        restyle(
+            &mut styled,
+            &[&inline],
+            None,
+            &ElementState::default(),
+            &changed,
+        );
+        assert_same_tree(&styled, &style_tree(&root, &inline, None));
+        assert_eq!(styled.children.len(), 3);
+
+        // ブロックになった兄弟の隣の空白は取り除かれる
+        let changed = [&block.rules[0].selectors[0]];
+        restyle(
+            &mut styled,
+            &[&block],
+            None,
+            &ElementState::default(),
+            &changed,
+        );
+        assert_same_tree(&styled, &style_tree(&root, &block, None));
+    }
+
+    #[test]
+    fn var_resolves_root_custom_property() {
+        let css = ":root { --c: red; } p { color: var(--c); }";
+        with_style("<div><p>a</p></div>", css, |root| {
+            assert_eq!(root.children[0].value_cloned("color"), color("#ff0000"));
+        });
+    }
+
+    #[test]
+    fn root_matches_only_the_document_root() {
+        let css = ":root { margin: 1px; } div { --m: 2px; } p { padding: var(--m); }";
+        with_style("<div><p>a</p></div>", css, |root| {
+            assert_eq!(
+                root.value_cloned("margin"),
+                Some(Value::Length(1.0, Unit::Px))
+            );
+            let p = &root.children[0];
+            assert_eq!(p.value_cloned("margin"), Some(Value::Length(0.0, Unit::Px)));
+            assert_eq!(
+                p.value_cloned("padding"),
+                Some(Value::Length(2.0, Unit::Px))
+            );
+        });
+    }
+
+    #[test]
+    fn var_with_several_values() {
+        let css = "div { --pad: 1px 2px; --copy: var(--pad); padding: var(--pad); }";
+        with_style("<div></div>", css, |div| {
+            // 複数の値はpaddingの一つの値にならないので、宣言がなかったものとして初期値を使う
+            assert_eq!(
+                div.value_cloned("padding"),
+                Some(Value::Length(0.0, Unit::Px))
+            );
+            assert_eq!(div.value_cloned("--copy"), div.value_cloned("--pad"));
+        });
+    }
+
+    #[test]
+    fn var_nested_fallbacks() {
+        let css = "p { color: var(--a, var(--b, green)); }";
+        with_style("<div><p>a</p></div>", css, |root| {
+            assert_eq!(root.children[0].value_cloned("color"), color("#008000"));
+        });
+    }
+
+    #[test]
+    fn var_cycles_are_invalid() {
+        let css = ":root { --a: var(--b, red); --b: var(--a, blue); --c: var(--c); } \
+                   p { color: var(--a); background-color: var(--c, green); }";
+        // HashMapの順序に依存しないことを確かめるため、何度か繰り返す
+        for _ in 0..20 {
+            with_style("<div><p>a</p></div>", css, |root| {
+                assert_eq!(root.value("--a"), None);
+                assert_eq!(root.value("--b"), None);
+                assert_eq!(root.value("--c"), None);
+                let p = &root.children[0];
+                assert_eq!(p.value_cloned("color"), color("#000000"));
+                assert_eq!(p.value_cloned("background-color"), color("#008000"));
+            });
+        }
+    }
+}
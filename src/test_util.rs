@@ -0,0 +1,143 @@
+use crate::layout::{
+    self, AnonymousBlock, BlockNode, BoxType, InlineNode, LayoutBox, Rect, Viewport,
+};
+use crate::painting::{self, Canvas};
+use crate::style::StyledNode;
+use crate::{css, html, style};
+
+/// Parse `html` and `css`, style the document and pass the styled root to `f`.
+pub fn with_style<R>(html: &str, css: &str, f: impl FnOnce(&StyledNode) -> R) -> R {
+    let root = html::parse(html.to_string());
+    let stylesheet = css::parse(css.to_string());
+    f(&style::style_tree(&root, &stylesheet, None))
+}
+
+/// Parse `html` and `css`, lay the document out in a viewport `width` pixels wide,
+/// and pass the root box to `f`.
+pub fn with_layout<R>(html: &str, css: &str, width: f32, f: impl FnOnce(&LayoutBox) -> R) -> R {
+    let mut viewport: Viewport = Default::default();
+    viewport.dimensions.content.width = width;
+    with_layout_in(html, css, viewport, f)
+}
+
+/// Like `with_layout`, but lays the document out in `viewport`.
+pub fn with_layout_in<R>(
+    html: &str,
+    css: &str,
+    viewport: Viewport,
+    f: impl FnOnce(&LayoutBox) -> R,
+) -> R {
+    let root = html::parse(html.to_string());
+    let stylesheet = css::parse(css.to_string());
+    let styled = style::style_tree(&root, &stylesheet, None);
+    f(&layout::layout_tree(&styled, viewport))
+}
+
+/// Lay the document out in a `width` × `height` viewport and paint it at scale 1.
+pub fn render(html: &str, css: &str, width: f32, height: f32) -> Canvas {
+    let mut viewport: Viewport = Default::default();
+    viewport.dimensions.content.width = width;
+    viewport.dimensions.content.height = height;
+    with_layout_in(html, css, viewport, |root| {
+        painting::paint(root, viewport.dimensions.content, 1.0)
+    })
+}
+
+/// Assert that two layout trees have the same shape and box types, and that their
+/// dimensions agree to within `epsilon`. On failure, the panic message names the path
+/// of the first differing box, e.g. `root/0/2`.
+pub fn assert_layout_eq(actual: &LayoutBox, expected: &LayoutBox, epsilon: f32) {
+    if let Err(message) = compare_boxes(actual, expected, epsilon, "root") {
+        panic!("layout trees differ: {}", message);
+    }
+}
+
+fn compare_boxes(
+    actual: &LayoutBox,
+    expected: &LayoutBox,
+    epsilon: f32,
+    path: &str,
+) -> Result<(), String> {
+    if box_type_name(&actual.box_type) != box_type_name(&expected.box_type) {
+        return Err(format!(
+            "{}: box type {} != {}",
+            path,
+            box_type_name(&actual.box_type),
+            box_type_name(&expected.box_type)
+        ));
+    }
+
+    let a = actual.dimensions;
+    let e = expected.dimensions;
+    let boxes = [
+        ("content", a.content_box(), e.content_box()),
+        ("padding box", a.padding_box(), e.padding_box()),
+        ("border box", a.border_box(), e.border_box()),
+        ("margin box", a.margin_box(), e.margin_box()),
+    ];
+    for (name, a, e) in boxes {
+        if !rect_approx_eq(a, e, epsilon) {
+            return Err(format!("{}: {} {:?} != {:?}", path, name, a, e));
+        }
+    }
+
+    if actual.children.len() != expected.children.len() {
+        return Err(format!(
+            "{}: {} children != {}",
+            path,
+            actual.children.len(),
+            expected.children.len()
+        ));
+    }
+    for (i, (a, e)) in actual.children.iter().zip(&expected.children).enumerate() {
+        compare_boxes(a, e, epsilon, &format!("{}/{}", path, i))?;
+    }
+    Ok(())
+}
+
+fn rect_approx_eq(a: Rect, b: Rect, epsilon: f32) -> bool {
+    (a.x - b.x).abs() <= epsilon
+        && (a.y - b.y).abs() <= epsilon
+        && (a.width - b.width).abs() <= epsilon
+        && (a.height - b.height).abs() <= epsilon
+}
+
+fn box_type_name(box_type: &BoxType) -> &'static str {
+    match *box_type {
+        BlockNode(_) => "block",
+        InlineNode(_) => "inline",
+        AnonymousBlock => "anonymous block",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = "<div><p>a</p><p>b</p></div>";
+    const CSS: &str = "div, p { display: block; height: 10px; }";
+
+    // 二回レイアウトして、片方の末端の箱だけを`delta`ずらす
+    fn compare_nudged(delta: f32, epsilon: f32) {
+        let root = html::parse(HTML.to_string());
+        let stylesheet = css::parse(CSS.to_string());
+        let styled = style::style_tree(&root, &stylesheet, None);
+        let mut viewport: Viewport = Default::default();
+        viewport.dimensions.content.width = 100.0;
+        let expected = layout::layout_tree(&styled, viewport);
+        let mut actual = layout::layout_tree(&styled, viewport);
+        actual.children[1].dimensions.content.y += delta;
+        assert_layout_eq(&actual, &expected, epsilon);
+    }
+
+    #[test]
+    fn layouts_within_epsilon_are_equal() {
+        compare_nudged(0.001, 0.01);
+    }
+
+    #[test]
+    #[should_panic(expected = "root/1: content")]
+    fn layouts_beyond_epsilon_name_the_differing_box() {
+        compare_nudged(0.1, 0.01);
+    }
+}
@@ -0,0 +1,119 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Measures the advance width of a run of text.
+pub trait TextMeasurer {
+    fn measure(&self, text: &str, font_size: f32) -> f32;
+}
+
+/// We don't have real font data yet, so approximate every glyph as half an em wide.
+pub struct MonospaceMeasurer;
+
+impl TextMeasurer for MonospaceMeasurer {
+    fn measure(&self, text: &str, font_size: f32) -> f32 {
+        text.chars().count() as f32 * font_size * 0.5
+    }
+}
+
+/// Measure a run of text, adding `letter_spacing` between each pair of adjacent characters.
+pub fn text_width(
+    measurer: &dyn TextMeasurer,
+    text: &str,
+    font_size: f32,
+    letter_spacing: f32,
+) -> f32 {
+    let char_count = text.chars().count();
+    let spacing = if char_count > 1 {
+        letter_spacing * (char_count - 1) as f32
+    } else {
+        0.0
+    };
+    measurer.measure(text, font_size) + spacing
+}
+
+/// Wraps a measurer and remembers the width of each (text, font size) pair, so
+/// repeated runs of the same text are only measured once.
+pub struct CachedMeasurer<M> {
+    measurer: M,
+    // font-sizeのビット列ごとにテキストを引く。&strで引けるので、ヒット時に確保しない
+    cache: RefCell<HashMap<u32, HashMap<String, f32>>>,
+}
+
+impl<M: TextMeasurer> CachedMeasurer<M> {
+    pub fn new(measurer: M) -> CachedMeasurer<M> {
+        CachedMeasurer {
+            measurer: measurer,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Replace the underlying measurer, discarding the widths measured with the old one.
+    pub fn set_measurer(&mut self, measurer: M) {
+        self.measurer = measurer;
+        self.cache.get_mut().clear();
+    }
+}
+
+impl<M: TextMeasurer> TextMeasurer for CachedMeasurer<M> {
+    fn measure(&self, text: &str, font_size: f32) -> f32 {
+        let key = font_size.to_bits();
+        if let Some(&width) = self
+            .cache
+            .borrow()
+            .get(&key)
+            .and_then(|runs| runs.get(text))
+        {
+            return width;
+        }
+        let width = self.measurer.measure(text, font_size);
+        self.cache
+            .borrow_mut()
+            .entry(key)
+            .or_default()
+            .insert(text.to_string(), width);
+        width
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn letter_spacing_goes_between_characters() {
+        let plain = text_width(&MonospaceMeasurer, "abcde", 16.0, 0.0);
+        let spaced = text_width(&MonospaceMeasurer, "abcde", 16.0, 2.0);
+        assert_eq!(spaced - plain, 8.0);
+        assert_eq!(text_width(&MonospaceMeasurer, "a", 16.0, 2.0), 8.0);
+    }
+
+    /// Measures like `MonospaceMeasurer`, counting the calls in `calls`.
+    pub struct CountingMeasurer<'a> {
+        pub calls: &'a Cell<usize>,
+    }
+
+    impl TextMeasurer for CountingMeasurer<'_> {
+        fn measure(&self, text: &str, font_size: f32) -> f32 {
+            self.calls.set(self.calls.get() + 1);
+            MonospaceMeasurer.measure(text, font_size)
+        }
+    }
+
+    #[test]
+    fn cached_measurer_measures_each_run_once() {
+        let calls = Cell::new(0);
+        let mut measurer = CachedMeasurer::new(CountingMeasurer { calls: &calls });
+        assert_eq!(measurer.measure("abcd", 16.0), 32.0);
+        assert_eq!(measurer.measure("abcd", 16.0), 32.0);
+        assert_eq!(calls.get(), 1);
+        // 文字サイズが違えば測り直す
+        assert_eq!(measurer.measure("abcd", 10.0), 20.0);
+        assert_eq!(calls.get(), 2);
+
+        let new_calls = Cell::new(0);
+        measurer.set_measurer(CountingMeasurer { calls: &new_calls });
+        assert_eq!(measurer.measure("abcd", 16.0), 32.0);
+        assert_eq!(new_calls.get(), 1);
+    }
+}